@@ -1,23 +1,222 @@
-use clap::{App, Arg};
+use crate::{AsOf, audit::AuditPolicy, policy::LockedAccountPolicy, precision::{PrecisionTable, Rounding}, read::InputFormat, report::ReportFormat, statement::StatementFormat};
+use clap::{App, AppSettings, Arg, SubCommand};
+
+pub enum Command {
+    // read infile and report, the default when no subcommand is given
+    Process,
+    Serve { listen: String, shards: usize },
+    VerifyJournal { file: String },
+    Balance { client: u16, as_of: AsOf },
+    Statement { client: u16, format: StatementFormat },
+}
 
 pub struct Args {
+    pub command: Command,
     pub infile: String,
+    pub input_format: Option<InputFormat>,
     pub verbose: bool,
+    pub output: Option<String>,
+    pub output_format: ReportFormat,
+    pub ledger_dir: Option<String>,
+    pub event_log: Option<String>,
+    pub workers: usize,
+    pub load_snapshot: Option<String>,
+    pub save_snapshot: Option<String>,
+    pub rejected: Option<String>,
+    pub rejected_format: String,
+    pub journal: Option<String>,
+    pub trial_balance: Option<String>,
+    pub audit: Option<AuditPolicy>,
+    pub locked_policy: LockedAccountPolicy,
+    pub authorization_expiry: Option<u64>,
+    pub admin: Option<String>,
+    pub precision: PrecisionTable,
 }
 
 impl Args {
     pub fn parse() -> Self {
         let matches = App::new("bankex")
             .version("0.1.0")
-            .arg(Arg::with_name("infile")
-                .takes_value(true).required(true).help("path and file to read from"))
-            .arg(Arg::with_name("verbose").short("v").long("verbose").help("debug and error output"))
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .arg(infile_arg())
+            .arg(input_format_arg())
+            .arg(Arg::with_name("verbose").short("v").long("verbose").global(true).help("debug and error output"))
+            .arg(Arg::with_name("output").short("o").long("output")
+                .takes_value(true).value_name("path").help("path and file to write the account report to, defaults to stdout"))
+            .arg(Arg::with_name("output-format").long("output-format")
+                .takes_value(true).possible_values(&["csv", "json", "jsonl"]).default_value("csv").help("format of the account report"))
+            .arg(Arg::with_name("ledger-dir").long("ledger-dir")
+                .takes_value(true).value_name("path").conflicts_with("workers").help("directory holding a persistent ledger, transactions are applied on top of its state"))
+            .arg(Arg::with_name("event-log").long("event-log")
+                .takes_value(true).value_name("path").conflicts_with_all(&["ledger-dir", "load-snapshot", "workers"])
+                .help("event log the ledger state is rebuilt from, accepted transactions are appended to it"))
+            .arg(Arg::with_name("workers").short("w").long("workers")
+                .takes_value(true).value_name("n").default_value("1")
+                .validator(positive_number)
+                .help("number of threads to process transactions on, accounts are sharded by client id"))
+            .arg(Arg::with_name("load-snapshot").long("load-snapshot")
+                .takes_value(true).value_name("path").help("snapshot file to restore ledger state from before reading transactions"))
+            .arg(Arg::with_name("save-snapshot").long("save-snapshot")
+                .takes_value(true).value_name("path").help("snapshot file to write ledger state to after reading transactions"))
+            .arg(Arg::with_name("rejected").long("rejected")
+                .takes_value(true).value_name("path").help("path and file to write rejected and ignored transactions to"))
+            .arg(Arg::with_name("rejected-format").long("rejected-format")
+                .takes_value(true).possible_values(&["csv", "json"]).default_value("csv").help("format of the rejected transactions file"))
+            .arg(Arg::with_name("journal").long("journal")
+                .takes_value(true).value_name("path").conflicts_with("workers")
+                .help("path and file to write the audit journal of every balance change to, one json object per line"))
+            .arg(Arg::with_name("trial-balance").long("trial-balance")
+                .takes_value(true).value_name("path").help("path and file to write the trial balance of the double entry books to"))
+            .arg(Arg::with_name("audit").long("audit").conflicts_with("workers")
+                .help("check the ledger invariants after every transaction and stop at the first one that breaks them"))
+            .arg(Arg::with_name("allow-negative-available").long("allow-negative-available").requires("audit")
+                .help("do not treat a negative available balance as a broken invariant"))
+            .arg(Arg::with_name("locked-allow").long("locked-allow").global(true)
+                .takes_value(true).value_name("types").validator(|v| LockedAccountPolicy::allowing(&v).map(|_| ()))
+                .help("comma separated transaction types a locked account still accepts, e.g. dispute,resolve. all are rejected when not given"))
+            .arg(Arg::with_name("authorization-expiry").long("authorization-expiry").global(true)
+                .takes_value(true).value_name("n").validator(positive_number)
+                .help("release an authorization that was not captured or voided after n more transactions on its account"))
+            .arg(Arg::with_name("precision").long("precision").global(true)
+                .takes_value(true).value_name("assets").validator(|v| PrecisionTable::from_spec(&v).map(|_| ()))
+                .help("comma separated decimal places per asset, e.g. BTC=8,JPY=0. other assets have 4"))
+            .arg(Arg::with_name("rounding").long("rounding").global(true)
+                .takes_value(true).possible_values(&["reject", "truncate", "half-up", "half-even", "half-down"]).default_value("half-even")
+                .help("what to do with amounts that have more decimal places than their asset allows"))
+            .arg(Arg::with_name("strict-precision").long("strict-precision").global(true).conflicts_with("rounding")
+                .help("same as --rounding reject, can not be given with --rounding"))
+            .arg(Arg::with_name("admin").long("admin")
+                .takes_value(true).value_name("path")
+                .help("operator file of unlock, adjust_credit and adjust_debit rows with a reason column, applied after infile"))
+            .subcommand(SubCommand::with_name("serve")
+                .about("accept transaction rows over tcp and keep one ledger for all connections")
+                .arg(Arg::with_name("listen").short("l").long("listen")
+                    .takes_value(true).value_name("addr").default_value("127.0.0.1:7878").help("address and port to listen on"))
+                .arg(Arg::with_name("shards").long("shards")
+                    .takes_value(true).value_name("n").default_value("8").validator(positive_number)
+                    .help("number of independently locked ledger shards, accounts are sharded by client id")))
+            .subcommand(SubCommand::with_name("verify-journal")
+                .about("recompute the hash chain of a journal written with --journal and report the first broken link")
+                .arg(Arg::with_name("file")
+                    .takes_value(true).required(true).help("path and file of the journal to verify")))
+            .subcommand(SubCommand::with_name("balance")
+                .about("print a client's account as it stood at a line of the input")
+                .arg(Arg::with_name("client").short("c").long("client")
+                    .takes_value(true).value_name("id").required(true).validator(client_id).help("client id to look up"))
+                .arg(Arg::with_name("as-of").long("as-of")
+                    .takes_value(true).value_name("n").required(true).validator(positive_number)
+                    .help("last input line to apply, or the number of accepted transactions with --seq"))
+                .arg(Arg::with_name("seq").long("seq").help("read --as-of as a transaction sequence number instead of a line"))
+                .arg(infile_arg())
+                .arg(input_format_arg()))
+            .subcommand(SubCommand::with_name("statement")
+                .about("print every row of a client with the account after it and whether it was accepted")
+                .arg(Arg::with_name("client").short("c").long("client")
+                    .takes_value(true).value_name("id").required(true).validator(client_id).help("client id to write the statement for"))
+                .arg(Arg::with_name("format").long("format")
+                    .takes_value(true).possible_values(&["csv", "text"]).default_value("csv").help("format of the statement"))
+                .arg(infile_arg())
+                .arg(input_format_arg()))
             .get_matches();
-        let infile = matches.value_of("infile").unwrap_or_default().to_string();
+        let command = match matches.subcommand() {
+            ("serve", Some(sub)) => Command::Serve {
+                listen: sub.value_of("listen").unwrap_or_default().to_string(),
+                shards: sub.value_of("shards").unwrap_or_default().parse().unwrap_or(1),
+            },
+            ("verify-journal", Some(sub)) => Command::VerifyJournal {
+                file: sub.value_of("file").unwrap_or_default().to_string(),
+            },
+            ("balance", Some(sub)) => {
+                let n = sub.value_of("as-of").unwrap_or_default().parse().unwrap_or_default();
+                Command::Balance {
+                    client: sub.value_of("client").unwrap_or_default().parse().unwrap_or_default(),
+                    as_of: if sub.is_present("seq") { AsOf::Seq(n) } else { AsOf::Line(n) },
+                }
+            },
+            ("statement", Some(sub)) => Command::Statement {
+                client: sub.value_of("client").unwrap_or_default().parse().unwrap_or_default(),
+                format: sub.value_of("format").and_then(StatementFormat::from_name).unwrap_or(StatementFormat::Csv),
+            },
+            _ => Command::Process,
+        };
+        // subcommands that read transactions take their own infile
+        let input = match matches.subcommand() {
+            ("balance", Some(sub)) | ("statement", Some(sub)) => sub,
+            _ => &matches,
+        };
+        let infile = input.value_of("infile").unwrap_or_default().to_string();
+        let input_format = input.value_of("input-format").and_then(InputFormat::from_name);
         let verbose = matches.is_present("verbose");
+        let output = matches.value_of("output").map(String::from);
+        let output_format = matches.value_of("output-format").and_then(ReportFormat::from_name).unwrap_or(ReportFormat::Csv);
+        let ledger_dir = matches.value_of("ledger-dir").map(String::from);
+        let event_log = matches.value_of("event-log").map(String::from);
+        let workers = matches.value_of("workers").unwrap_or_default().parse().unwrap_or(1);
+        let load_snapshot = matches.value_of("load-snapshot").map(String::from);
+        let save_snapshot = matches.value_of("save-snapshot").map(String::from);
+        let rejected = matches.value_of("rejected").map(String::from);
+        let rejected_format = matches.value_of("rejected-format").unwrap_or_default().to_string();
+        let journal = matches.value_of("journal").map(String::from);
+        let trial_balance = matches.value_of("trial-balance").map(String::from);
+        let audit = if matches.is_present("audit") {
+            Some(AuditPolicy { allow_negative_available: matches.is_present("allow-negative-available") })
+        } else {
+            None
+        };
+        let locked_policy = matches.value_of("locked-allow").map(|v| LockedAccountPolicy::allowing(v).unwrap_or_default()).unwrap_or_default();
+        let authorization_expiry = matches.value_of("authorization-expiry").and_then(|v| v.parse().ok());
+        let admin = matches.value_of("admin").map(String::from);
+        let precision = matches.value_of("precision").map(|v| PrecisionTable::from_spec(v).unwrap_or_default()).unwrap_or_default()
+            .with_rounding(if matches.is_present("strict-precision") {
+                Rounding::Reject
+            } else {
+                matches.value_of("rounding").and_then(Rounding::from_name).unwrap_or_default()
+            });
         Self {
+            command,
             infile,
+            input_format,
             verbose,
+            output,
+            output_format,
+            ledger_dir,
+            event_log,
+            workers,
+            load_snapshot,
+            save_snapshot,
+            rejected,
+            rejected_format,
+            journal,
+            trial_balance,
+            audit,
+            locked_policy,
+            authorization_expiry,
+            admin,
+            precision,
         }
     }
 }
+
+fn infile_arg() -> Arg<'static, 'static> {
+    Arg::with_name("infile")
+        .takes_value(true).required(true).help("path and file to read from")
+}
+
+fn input_format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("input-format").long("input-format")
+        .takes_value(true).possible_values(&["csv", "jsonl"]).help("format of infile, detected from the extension when not given")
+}
+
+fn client_id(v: String) -> Result<(), String> {
+    match v.parse::<u16>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err("must be a client id between 0 and 65535".to_string()),
+    }
+}
+
+fn positive_number(v: String) -> Result<(), String> {
+    match v.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("must be a positive number".to_string()),
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{AccountKey, AccountStatus, AuthorizationState, DisputeState, Transaction, TransactionRecord, TransactionType};
+use crate::error::LedgerError;
+use crate::journal::Balances;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct AuditPolicy {
+    pub allow_negative_available: bool,
+}
+
+// checks the ledger invariants after every transaction. the funds under open disputes are tallied per account
+// from the dispute state of the stored transactions, apart from the held balance they have to agree with.
+// open authorizations are tallied the same way against the authorized balance
+#[derive(Debug, Default)]
+pub struct Audit {
+    policy: AuditPolicy,
+    open_disputes: HashMap<AccountKey, Decimal>,
+    open_authorizations: HashMap<AccountKey, Decimal>,
+}
+
+impl Audit {
+    // tallies the disputes already open in records, so audit can be switched on for a ledger that has state
+    pub fn new<'a>(policy: AuditPolicy, records: impl Iterator<Item = &'a TransactionRecord>) -> Self {
+        let mut open_disputes: HashMap<AccountKey, Decimal> = HashMap::new();
+        let mut open_authorizations: HashMap<AccountKey, Decimal> = HashMap::new();
+        for record in records {
+            let amount = record.transaction.amount.unwrap_or_default();
+            if record.authorization.as_ref().is_some_and(|a| a.state == AuthorizationState::Open) {
+                let key = (record.transaction.client.unwrap_or_default(), record.transaction.asset.clone());
+                *open_authorizations.entry(key).or_default() += amount;
+            }
+            if record.state != DisputeState::Disputed {
+                continue;
+            }
+            // a disputed transfer is held on the destination's account
+            let holder = match record.transaction.transaction_type {
+                TransactionType::Transfer => record.transaction.destination,
+                _ => record.transaction.client,
+            };
+            let key = (holder.unwrap_or_default(), record.transaction.asset.clone());
+            *open_disputes.entry(key).or_default() += amount;
+        }
+        Self { policy, open_disputes, open_authorizations }
+    }
+
+    // an authorization of the account ran out without a transaction naming it
+    pub fn expire(&mut self, key: &AccountKey, amount: Decimal) {
+        *self.open_authorizations.entry(key.clone()).or_default() -= amount;
+    }
+
+    pub fn policy(&self) -> AuditPolicy {
+        self.policy
+    }
+
+    // trans was accepted, record is the stored transaction it refers to and after the account it left behind.
+    // allowed_when_locked says whether the locked account policy lets trans change a locked account
+    pub fn check(&mut self, trans: &Transaction, record: Option<&TransactionRecord>, before: Option<&Balances>, after: &AccountStatus, allowed_when_locked: bool) -> Result<(), LedgerError> {
+        let referenced = record.and_then(|r| r.transaction.amount).unwrap_or_default();
+        let key = after.key();
+        match trans.transaction_type {
+            TransactionType::Dispute => *self.open_disputes.entry(key.clone()).or_default() += referenced,
+            TransactionType::Resolve | TransactionType::Chargeback => *self.open_disputes.entry(key.clone()).or_default() -= referenced,
+            TransactionType::Authorize => *self.open_authorizations.entry(key.clone()).or_default() += referenced,
+            TransactionType::Capture | TransactionType::Void => *self.open_authorizations.entry(key.clone()).or_default() -= referenced,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer | TransactionType::Unlock
+                | TransactionType::AdjustCredit | TransactionType::AdjustDebit => {},
+        }
+        self.check_account(trans, before, after, allowed_when_locked)
+    }
+
+    // the invariants of an account trans left behind, also used for the second account a transfer or the
+    // chargeback of one changes, whose open disputes and authorizations trans does not change
+    pub fn check_account(&self, trans: &Transaction, before: Option<&Balances>, after: &AccountStatus, allowed_when_locked: bool) -> Result<(), LedgerError> {
+        let key = after.key();
+        let violated = |invariant: &'static str, detail: String| LedgerError::InvariantViolated {
+            client: after.client,
+            tx: trans.tx.unwrap_or_default(),
+            line: trans.line,
+            invariant,
+            detail,
+        };
+        if after.total != after.available + after.held + after.authorized {
+            return Err(violated("total_is_available_plus_held", format!("total:[{}] available:[{}] held:[{}] authorized:[{}]", after.total, after.available, after.held, after.authorized)));
+        }
+        if after.held.is_sign_negative() && !after.held.is_zero() {
+            return Err(violated("held_not_negative", format!("held:[{}]", after.held)));
+        }
+        if after.authorized.is_sign_negative() && !after.authorized.is_zero() {
+            return Err(violated("authorized_not_negative", format!("authorized:[{}]", after.authorized)));
+        }
+        if after.available.is_sign_negative() && !after.available.is_zero() && !self.policy.allow_negative_available {
+            return Err(violated("available_not_negative", format!("available:[{}]", after.available)));
+        }
+        if let Some(before) = before.filter(|b| b.locked && !allowed_when_locked) {
+            if *before != Balances::of(after) {
+                return Err(violated("locked_unchanged", format!("before:[{:?}] after:[{:?}]", before, after)));
+            }
+        }
+        let open = self.open_disputes.get(&key).copied().unwrap_or_default();
+        if after.held != open {
+            return Err(violated("held_matches_disputes", format!("held:[{}] open disputes:[{}]", after.held, open)));
+        }
+        let open = self.open_authorizations.get(&key).copied().unwrap_or_default();
+        if after.authorized != open {
+            return Err(violated("authorized_matches_authorizations", format!("authorized:[{}] open authorizations:[{}]", after.authorized, open)));
+        }
+        Ok(())
+    }
+}
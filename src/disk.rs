@@ -0,0 +1,227 @@
+use crate::{AccountStatus, Ledger, Transaction, TransactionRecord};
+use crate::audit::AuditPolicy;
+use crate::error::LedgerError;
+use crate::journal::JournalSink;
+use crate::policy::LockedAccountPolicy;
+use crate::postings::TrialBalance;
+use crate::ledger::InMemoryLedger;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const LOG_FILE: &str = "ledger.log";
+const ACCOUNTS_INDEX: &str = "accounts.idx";
+const TRANSACTIONS_INDEX: &str = "transactions.idx";
+
+// one line of the append-only log or an index file, the latest entry for an account or transaction id wins on replay.
+// the log has one outcome per transaction so a crash mid write loses the whole transaction, never half of it
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum LogEntry {
+    Account(AccountStatus),
+    Transaction(TransactionRecord),
+    Outcome { accounts: Vec<AccountStatus>, transactions: Vec<TransactionRecord> },
+}
+
+// ledger kept in a directory so state survives between runs. every accepted transaction appends the
+// accounts and transaction records it changed to ledger.log as one line, compact folds the log into the index files.
+// on open the index files are loaded and the log is replayed on top of them, so a restart never needs the original input
+pub struct DiskLedger {
+    dir: PathBuf,
+    state: InMemoryLedger,
+    log: BufWriter<File>,
+}
+
+impl DiskLedger {
+    pub fn open(dir: &Path) -> Result<Self, LedgerError> {
+        fs::create_dir_all(dir)?;
+        let mut state = InMemoryLedger::default();
+        Self::load(&dir.join(ACCOUNTS_INDEX), &mut state)?;
+        Self::load(&dir.join(TRANSACTIONS_INDEX), &mut state)?;
+        let torn = Self::load(&dir.join(LOG_FILE), &mut state)?;
+        state.rebuild_postings();
+        let log = OpenOptions::new().create(true).append(true).open(dir.join(LOG_FILE))?;
+        let mut ledger = Self {
+            dir: dir.to_path_buf(),
+            state,
+            log: BufWriter::new(log),
+        };
+        if torn {
+            ledger.compact()?; // drop the partially written last line before anything is appended after it
+        }
+        Ok(ledger)
+    }
+
+    // returns true when the last line could not be parsed, which happens if the process died mid write
+    fn load(path: &Path, state: &mut InMemoryLedger) -> Result<bool, LedgerError> {
+        let f = match File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = BufReader::new(f).lines().peekable();
+        while let Some(line) = lines.next() {
+            let line = line?;
+            let entry: LogEntry = match serde_json::from_str(&line) {
+                Ok(v) => v,
+                Err(_) if lines.peek().is_none() => return Ok(true),
+                Err(e) => return Err(LedgerError::Storage { reason: format!("{}: {}", path.display(), e) }),
+            };
+            let (accounts, records) = match entry {
+                LogEntry::Account(cas) => (vec![cas], Vec::new()),
+                LogEntry::Transaction(record) => (Vec::new(), vec![record]),
+                LogEntry::Outcome { accounts, transactions } => (accounts, transactions),
+            };
+            for cas in accounts {
+                state.by_account.insert(cas.key(), cas);
+            }
+            for record in records {
+                let tid = record.transaction.tx.unwrap_or_default();
+                state.by_transaction_id.insert(tid, record);
+            }
+        }
+        Ok(false)
+    }
+
+    // a refused capture or void can still have released the expired authorization it named, that change is logged too
+    fn record_outcome(&mut self, trans: &Transaction, result: &Result<(), LedgerError>) -> Result<(), LedgerError> {
+        if result.is_ok() || !self.state.expired().is_empty() {
+            self.append_outcome(trans)?;
+        }
+        Ok(())
+    }
+
+    // logs the accounts and transaction records an accepted transaction left behind, including authorizations it
+    // expired, and hands the line to the os before the transaction counts as accepted so it outlives the process
+    fn append_outcome(&mut self, trans: &Transaction) -> Result<(), LedgerError> {
+        let accounts = self.state.account_of(trans).into_iter().chain(self.state.counterparty_of(trans)).cloned().collect();
+        let transactions = trans.tx.into_iter().chain(self.state.expired().iter().copied())
+            .filter_map(|tid| self.state.by_transaction_id.get(&tid))
+            .cloned()
+            .collect();
+        let mut line = serde_json::to_vec(&LogEntry::Outcome { accounts, transactions }).map_err(std::io::Error::from)?;
+        line.push(b'\n');
+        self.log.write_all(&line)?;
+        self.log.flush()?;
+        Ok(())
+    }
+
+    pub fn set_journal(&mut self, journal: Box<dyn JournalSink>) {
+        self.state.set_journal(journal);
+    }
+
+    pub fn take_journal(&mut self) -> Option<Box<dyn JournalSink>> {
+        self.state.take_journal()
+    }
+
+    pub fn set_audit(&mut self, policy: AuditPolicy) {
+        self.state.set_audit(policy);
+    }
+
+    pub fn set_locked_policy(&mut self, policy: LockedAccountPolicy) {
+        self.state.set_locked_policy(policy);
+    }
+
+    pub fn set_authorization_expiry(&mut self, transactions: u64) {
+        self.state.set_authorization_expiry(transactions);
+    }
+
+    pub fn flush(&mut self) -> Result<(), LedgerError> {
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    // rewrite the index files from the current state and start a fresh log, the index files are
+    // written under a temporary name and renamed so a crash leaves either the old or the new version
+    pub fn compact(&mut self) -> Result<(), LedgerError> {
+        self.log.flush()?;
+        let accounts = self.state.by_account.values().cloned().map(LogEntry::Account);
+        Self::write_index(&self.dir.join(ACCOUNTS_INDEX), accounts)?;
+        let records = self.state.by_transaction_id.values().cloned().map(LogEntry::Transaction);
+        Self::write_index(&self.dir.join(TRANSACTIONS_INDEX), records)?;
+        let log = File::create(self.dir.join(LOG_FILE))?;
+        log.sync_all()?;
+        let log = OpenOptions::new().append(true).open(self.dir.join(LOG_FILE))?;
+        self.log = BufWriter::new(log);
+        Ok(())
+    }
+
+    fn write_index(path: &Path, entries: impl Iterator<Item = LogEntry>) -> Result<(), LedgerError> {
+        let tmp = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        for entry in entries {
+            serde_json::to_writer(&mut out, &entry).map_err(std::io::Error::from)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        out.get_ref().sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+impl Drop for DiskLedger {
+    fn drop(&mut self) {
+        let _ = self.log.flush();
+    }
+}
+
+impl Ledger for DiskLedger {
+    // errors leave the in memory state unchanged so only accepted transactions reach the log
+    fn process_transaction(&mut self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
+        let result = self.state.process_transaction(verbose, trans);
+        self.record_outcome(trans, &result)?;
+        result
+    }
+
+    fn process_admin(&mut self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
+        let result = self.state.process_admin(verbose, trans);
+        self.record_outcome(trans, &result)?;
+        result
+    }
+
+    fn get_funds_available(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        self.state.get_funds_available(client_id)
+    }
+
+    fn get_funds_held(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        self.state.get_funds_held(client_id)
+    }
+
+    fn get_funds_total(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        self.state.get_funds_total(client_id)
+    }
+
+    fn verify_transaction(&self, trans: &Transaction) -> Result<(), LedgerError> {
+        self.state.verify_transaction(trans)
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = &AccountStatus> + '_> {
+        self.state.accounts()
+    }
+
+    fn account_of(&self, trans: &Transaction) -> Option<&AccountStatus> {
+        self.state.account_of(trans)
+    }
+
+    fn counterparty_of(&self, trans: &Transaction) -> Option<&AccountStatus> {
+        self.state.counterparty_of(trans)
+    }
+
+    fn trial_balance(&self) -> TrialBalance {
+        self.state.trial_balance()
+    }
+
+    fn save_snapshot(&self, out: &mut dyn Write) -> Result<(), LedgerError> {
+        self.state.save_snapshot(out)
+    }
+
+    // the restored state is written straight to the index files so the old log can not be replayed over it
+    fn load_snapshot(&mut self, rdr: &mut dyn Read) -> Result<(), LedgerError> {
+        self.state.load_snapshot(rdr)?;
+        self.compact()
+    }
+}
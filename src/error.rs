@@ -0,0 +1,295 @@
+use crate::{AuthorizationState, DisputeState};
+use rust_decimal::Decimal;
+use std::{error::Error, fmt, io};
+
+// errors raised while applying a transaction to a ledger, carry the ids involved so callers can match on them
+#[derive(Debug, PartialEq, Clone)]
+pub enum LedgerError {
+    MissingClient { tx: Option<u32> },
+    MissingTransactionId { client: Option<u16> },
+    MissingAmount { client: u16, tx: u32 },
+    UnexpectedAmount { client: u16, tx: u32, amount: Decimal },
+    DuplicateTransaction { client: u16, tx: u32 },
+    InsufficientFunds { client: u16, tx: u32, amount: Decimal, available: Decimal },
+    AccountLocked { client: u16, tx: u32, transaction_type: &'static str },
+    UnknownAccount { client: u16 },
+    UnknownTransaction { client: u16, tx: u32 },
+    ClientMismatch { client: u16, tx: u32, owner: Option<u16> },
+    IllegalTransition { tx: u32, from: DisputeState, to: DisputeState },
+    Storage { reason: String },
+    UnsupportedSnapshot { version: u32, expected: u32 },
+    Unbalanced { account: String, expected: Decimal, found: Decimal },
+    AdminOnly { client: u16, tx: u32, transaction_type: &'static str },
+    NotAdmin { client: u16, tx: u32, transaction_type: &'static str },
+    MissingReason { client: u16, tx: u32 },
+    NotDisputable { client: u16, tx: u32, transaction_type: &'static str },
+    AssetMismatch { client: u16, tx: u32, asset: String, owner_asset: Option<String> },
+    MissingDestination { client: u16, tx: u32 },
+    SelfTransfer { client: u16, tx: u32 },
+    InvalidAmount { client: u16, tx: u32, amount: Decimal, transaction_type: &'static str },
+    NotAuthorization { client: u16, tx: u32, transaction_type: &'static str },
+    AuthorizationClosed { client: u16, tx: u32, state: AuthorizationState },
+    AuthorizationExpired { client: u16, tx: u32, expires_at: u64 },
+    CaptureExceedsAuthorization { client: u16, tx: u32, amount: Decimal, authorized: Decimal },
+    InvariantViolated { client: u16, tx: u32, line: Option<u64>, invariant: &'static str, detail: String },
+}
+
+impl LedgerError {
+    // the ledger can not be trusted after these, so a run stops instead of treating the row as rejected
+    pub fn aborts_run(&self) -> bool {
+        matches!(self, LedgerError::InvariantViolated { .. })
+    }
+
+    // stable machine readable reason, used in the rejected transaction report
+    pub fn code(&self) -> &'static str {
+        match self {
+            LedgerError::MissingClient { .. } => "missing_client",
+            LedgerError::MissingTransactionId { .. } => "missing_tx",
+            LedgerError::MissingAmount { .. } => "missing_amount",
+            LedgerError::UnexpectedAmount { .. } => "unexpected_amount",
+            LedgerError::DuplicateTransaction { .. } => "duplicate_tx",
+            LedgerError::InsufficientFunds { .. } => "insufficient_funds",
+            LedgerError::AccountLocked { .. } => "account_locked",
+            LedgerError::UnknownAccount { .. } => "unknown_account",
+            LedgerError::UnknownTransaction { .. } => "unknown_tx",
+            LedgerError::ClientMismatch { .. } => "client_mismatch",
+            LedgerError::IllegalTransition { .. } => "illegal_transition",
+            LedgerError::Storage { .. } => "storage",
+            LedgerError::UnsupportedSnapshot { .. } => "unsupported_snapshot",
+            LedgerError::Unbalanced { .. } => "unbalanced",
+            LedgerError::AdminOnly { .. } => "admin_only",
+            LedgerError::NotAdmin { .. } => "not_admin",
+            LedgerError::MissingReason { .. } => "missing_reason",
+            LedgerError::NotDisputable { .. } => "not_disputable",
+            LedgerError::AssetMismatch { .. } => "asset_mismatch",
+            LedgerError::MissingDestination { .. } => "missing_destination",
+            LedgerError::SelfTransfer { .. } => "self_transfer",
+            LedgerError::InvalidAmount { .. } => "invalid_amount",
+            LedgerError::NotAuthorization { .. } => "not_authorization",
+            LedgerError::AuthorizationClosed { .. } => "authorization_closed",
+            LedgerError::AuthorizationExpired { .. } => "authorization_expired",
+            LedgerError::CaptureExceedsAuthorization { .. } => "capture_exceeds_authorization",
+            LedgerError::InvariantViolated { .. } => "invariant_violated",
+        }
+    }
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerError::MissingClient { tx } =>
+                write!(f, "need client id from transaction id:[{:?}]", tx),
+            LedgerError::MissingTransactionId { client } =>
+                write!(f, "need transaction id from transaction for client id:[{:?}]", client),
+            LedgerError::MissingAmount { client, tx } =>
+                write!(f, "need amount from transaction id:[{}] client id:[{}]", tx, client),
+            LedgerError::UnexpectedAmount { client, tx, amount } =>
+                write!(f, "unexpected amount:[{}] on transaction id:[{}] client id:[{}]", amount, tx, client),
+            LedgerError::DuplicateTransaction { client, tx } =>
+                write!(f, "duplicate transaction id:[{}] client id:[{}]", tx, client),
+            LedgerError::InsufficientFunds { client, tx, amount, available } =>
+                write!(f, "insufficient funds for transaction id:[{}] client id:[{}] amount:[{}] available:[{}]", tx, client, amount, available),
+            LedgerError::AccountLocked { client, tx, transaction_type } =>
+                write!(f, "client account:[{}] locked and the locked account policy rejects {} transaction id:[{}]", client, transaction_type, tx),
+            LedgerError::UnknownAccount { client } =>
+                write!(f, "account status for client id:[{}] not found", client),
+            LedgerError::UnknownTransaction { client, tx } =>
+                write!(f, "transaction id:[{}] for client id:[{}] not found", tx, client),
+            LedgerError::ClientMismatch { client, tx, owner } =>
+                write!(f, "found transaction id:[{}] however it belongs to client id:[{:?}] not client id:[{}]", tx, owner, client),
+            LedgerError::IllegalTransition { tx, from, to } =>
+                write!(f, "transaction id:[{}] can not move from {:?} to {:?}", tx, from, to),
+            LedgerError::Storage { reason } =>
+                write!(f, "ledger storage failure: {}", reason),
+            LedgerError::UnsupportedSnapshot { version, expected } =>
+                write!(f, "snapshot version:[{}] is not supported, expected version:[{}]", version, expected),
+            LedgerError::Unbalanced { account, expected, found } =>
+                write!(f, "trial balance failed for account:[{}] expected:[{}] found:[{}]", account, expected, found),
+            LedgerError::AdminOnly { client, tx, transaction_type } =>
+                write!(f, "{} transaction id:[{}] client id:[{}] is only accepted from admin input", transaction_type, tx, client),
+            LedgerError::NotAdmin { client, tx, transaction_type } =>
+                write!(f, "{} transaction id:[{}] client id:[{}] is not an admin transaction", transaction_type, tx, client),
+            LedgerError::MissingReason { client, tx } =>
+                write!(f, "need operator reason from admin transaction id:[{}] client id:[{}]", tx, client),
+            LedgerError::NotDisputable { client, tx, transaction_type } =>
+                write!(f, "{} transaction id:[{}] client id:[{}] can not be disputed", transaction_type, tx, client),
+            LedgerError::AssetMismatch { client, tx, asset, owner_asset } =>
+                write!(f, "transaction id:[{}] of client id:[{}] is in asset:[{}] not asset:[{}]", tx, client, owner_asset.as_deref().unwrap_or_default(), asset),
+            LedgerError::MissingDestination { client, tx } =>
+                write!(f, "need destination client from transfer id:[{}] client id:[{}]", tx, client),
+            LedgerError::SelfTransfer { client, tx } =>
+                write!(f, "transfer id:[{}] client id:[{}] has the same client as destination", tx, client),
+            LedgerError::InvalidAmount { client, tx, amount, transaction_type } =>
+                write!(f, "amount:[{}] is not valid for {} transaction id:[{}] client id:[{}]", amount, transaction_type, tx, client),
+            LedgerError::NotAuthorization { client, tx, transaction_type } =>
+                write!(f, "{} transaction id:[{}] client id:[{}] is not an authorization", transaction_type, tx, client),
+            LedgerError::AuthorizationClosed { client, tx, state } =>
+                write!(f, "authorization id:[{}] client id:[{}] is already {:?}", tx, client, state),
+            LedgerError::AuthorizationExpired { client, tx, expires_at } =>
+                write!(f, "authorization id:[{}] client id:[{}] expired at:[{}]", tx, client, expires_at),
+            LedgerError::CaptureExceedsAuthorization { client, tx, amount, authorized } =>
+                write!(f, "capture amount:[{}] of authorization id:[{}] client id:[{}] is more than the authorized:[{}]", amount, tx, client, authorized),
+            LedgerError::InvariantViolated { client, tx, line, invariant, detail } =>
+                write!(f, "invariant:[{}] violated by transaction id:[{}] client id:[{}] at line:[{:?}] {}", invariant, tx, client, line, detail),
+        }
+    }
+}
+
+impl Error for LedgerError {}
+
+impl From<io::Error> for LedgerError {
+    fn from(e: io::Error) -> Self {
+        LedgerError::Storage { reason: e.to_string() }
+    }
+}
+
+// errors raised while pulling transactions from the input
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    MalformedRow { line: Option<u64>, fields: Vec<String>, source: Box<dyn Error + Send + Sync> },
+    MissingTransactionId { line: Option<u64>, fields: Vec<String> },
+    TooPrecise { line: Option<u64>, fields: Vec<String>, places: u32, max: u32 },
+}
+
+impl ReadError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ReadError::Io(_) => "io",
+            ReadError::MalformedRow { .. } => "malformed_row",
+            ReadError::MissingTransactionId { .. } => "missing_tx",
+            ReadError::TooPrecise { .. } => "too_precise",
+        }
+    }
+
+    pub fn line(&self) -> Option<u64> {
+        match self {
+            ReadError::Io(_) => None,
+            ReadError::MalformedRow { line, .. } => *line,
+            ReadError::MissingTransactionId { line, .. } => *line,
+            ReadError::TooPrecise { line, .. } => *line,
+        }
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "unable to read input: {}", e),
+            ReadError::MalformedRow { line, source, .. } => write!(f, "malformed row at line:[{:?}] reason:[{}]", line, source),
+            ReadError::MissingTransactionId { line, .. } => write!(f, "no transaction id at line:[{:?}]", line),
+            ReadError::TooPrecise { line, places, max, .. } =>
+                write!(f, "amount at line:[{:?}] has {} decimal places but its asset allows {}", line, places, max),
+        }
+    }
+}
+
+impl Error for ReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReadError::Io(e) => Some(e),
+            ReadError::MalformedRow { source, .. } => Some(source.as_ref()),
+            ReadError::MissingTransactionId { .. } | ReadError::TooPrecise { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<csv::Error> for ReadError {
+    fn from(e: csv::Error) -> Self {
+        if e.is_io_error() {
+            return ReadError::Io(e.into());
+        }
+        let line = e.position().map(|p| p.line());
+        ReadError::MalformedRow { line, fields: Vec::new(), source: Box::new(e) }
+    }
+}
+
+// reasons a journal fails verification, line is the 1 based line of the journal file
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    Malformed { line: u64, reason: String },
+    PrevHashMismatch { line: u64, seq: u64, expected: String, found: String },
+    HashMismatch { line: u64, seq: u64, expected: String, found: String },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Io(e) => write!(f, "unable to read journal: {}", e),
+            JournalError::Malformed { line, reason } =>
+                write!(f, "journal line:[{}] can not be read reason:[{}]", line, reason),
+            JournalError::PrevHashMismatch { line, seq, expected, found } =>
+                write!(f, "broken link at line:[{}] seq:[{}], previous hash is:[{}] but the line before hashes to:[{}]", line, seq, found, expected),
+            JournalError::HashMismatch { line, seq, expected, found } =>
+                write!(f, "broken link at line:[{}] seq:[{}], recorded hash is:[{}] but the contents hash to:[{}]", line, seq, found, expected),
+        }
+    }
+}
+
+impl Error for JournalError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            JournalError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self {
+        JournalError::Io(e)
+    }
+}
+
+// top of the hierarchy, returned by operations that both read input and apply it to a ledger
+#[derive(Debug)]
+pub enum BankexError {
+    Read(ReadError),
+    Ledger(LedgerError),
+    Output(io::Error),
+}
+
+impl fmt::Display for BankexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BankexError::Read(e) => write!(f, "{}", e),
+            BankexError::Ledger(e) => write!(f, "{}", e),
+            BankexError::Output(e) => write!(f, "unable to write output: {}", e),
+        }
+    }
+}
+
+impl Error for BankexError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BankexError::Read(e) => Some(e),
+            BankexError::Ledger(e) => Some(e),
+            BankexError::Output(e) => Some(e),
+        }
+    }
+}
+
+impl From<ReadError> for BankexError {
+    fn from(e: ReadError) -> Self {
+        BankexError::Read(e)
+    }
+}
+
+impl From<LedgerError> for BankexError {
+    fn from(e: LedgerError) -> Self {
+        BankexError::Ledger(e)
+    }
+}
+
+impl From<io::Error> for BankexError {
+    fn from(e: io::Error) -> Self {
+        BankexError::Output(e)
+    }
+}
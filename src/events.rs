@@ -0,0 +1,296 @@
+use crate::{AccountKey, AccountStatus, Authorization, Ledger, Transaction, TransactionType};
+use crate::audit::AuditPolicy;
+use crate::error::LedgerError;
+use crate::journal::JournalSink;
+use crate::ledger::InMemoryLedger;
+use crate::policy::LockedAccountPolicy;
+use crate::postings::TrialBalance;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+// an accepted transaction and its outcome, the account of the transaction's client right after it was applied.
+// seq numbers the events of a log from 1 without gaps
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Event {
+    pub seq: u64,
+    pub line: Option<u64>,
+    pub transaction: Transaction,
+    pub account: AccountStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counterparty: Option<AccountStatus>, // second account a transfer or its chargeback changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authorization: Option<Authorization>, // the hold an authorize opened, so it expires on replay as it did live
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refused: Option<String>, // reason of a refused capture or void that still released its expired authorization
+}
+
+// state derived from the event log. a projection only ever sees events in seq order, so any projection
+// can be rebuilt at any seq by replaying the log up to it
+pub trait Projection {
+    fn apply(&mut self, event: &Event) -> Result<(), LedgerError>;
+}
+
+// the full ledger, rebuilt by running every transaction through the same processing as live input.
+// an event the ledger would not accept again or that ends in a different account means the log and the
+// processing rules no longer agree. the locked account policy is not checked again, the event passed the
+// policy of the run that logged it
+impl Projection for InMemoryLedger {
+    fn apply(&mut self, event: &Event) -> Result<(), LedgerError> {
+        let mut transaction = event.transaction.clone();
+        transaction.line = event.line;
+        let result = self.replay_transaction(&transaction);
+        match (result, &event.refused) {
+            (Ok(()), None) => {},
+            (Err(e), Some(refused)) if e.code() == refused => {},
+            (Ok(()), Some(refused)) =>
+                return Err(LedgerError::Storage { reason: format!("event seq:[{}] was refused with:[{}] but now applies", event.seq, refused) }),
+            (Err(e), _) =>
+                return Err(LedgerError::Storage { reason: format!("event seq:[{}] no longer applies: {}", event.seq, e) }),
+        }
+        if let Some(record) = event.authorization.as_ref().zip(transaction.tx).and_then(|(_, tid)| self.by_transaction_id.get_mut(&tid)) {
+            record.authorization = event.authorization.clone();
+        }
+        for recorded in std::iter::once(&event.account).chain(event.counterparty.as_ref()) {
+            let replayed = self.by_account.get(&recorded.key());
+            if replayed.map(|cas| (cas.available, cas.held, cas.locked)) != Some((recorded.available, recorded.held, recorded.locked)) {
+                return Err(LedgerError::Storage { reason: format!("event seq:[{}] replays to account:[{:?}] but recorded:[{:?}]", event.seq, replayed, recorded) });
+            }
+        }
+        Ok(())
+    }
+}
+
+// balances only, taken from the recorded outcomes without processing the transactions again
+#[derive(Debug, Default)]
+pub struct AccountsProjection {
+    pub by_account: BTreeMap<AccountKey, AccountStatus>,
+}
+
+impl Projection for AccountsProjection {
+    fn apply(&mut self, event: &Event) -> Result<(), LedgerError> {
+        for cas in std::iter::once(&event.account).chain(event.counterparty.as_ref()) {
+            self.by_account.insert(cas.key(), cas.clone());
+        }
+        Ok(())
+    }
+}
+
+// append-only file with one event per line
+pub struct EventLog {
+    path: PathBuf,
+    out: BufWriter<File>,
+    last_seq: u64,
+}
+
+impl EventLog {
+    // a partially written last line, left by a process that died mid write, is cut off before anything is appended
+    pub fn open(path: &Path) -> Result<Self, LedgerError> {
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut last_seq = 0;
+        let mut good_len = 0;
+        let mut rdr = BufReader::new(&file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = rdr.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            match serde_json::from_str::<Event>(&line) {
+                Ok(event) if line.ends_with('\n') => last_seq = event.seq,
+                _ => break,
+            }
+            good_len += read as u64;
+        }
+        if good_len < file.metadata()?.len() {
+            file.set_len(good_len)?;
+        }
+        Ok(Self { path: path.to_path_buf(), out: BufWriter::new(file), last_seq })
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    pub fn append(&mut self, line: Option<u64>, transaction: &Transaction, account: &AccountStatus, counterparty: Option<&AccountStatus>, authorization: Option<&Authorization>, refused: Option<&str>) -> Result<u64, LedgerError> {
+        let event = Event {
+            seq: self.last_seq + 1,
+            line,
+            transaction: transaction.clone(),
+            account: account.clone(),
+            counterparty: counterparty.cloned(),
+            authorization: authorization.cloned(),
+            refused: refused.map(String::from),
+        };
+        serde_json::to_writer(&mut self.out, &event).map_err(std::io::Error::from)?;
+        self.out.write_all(b"\n")?;
+        self.last_seq = event.seq;
+        Ok(event.seq)
+    }
+
+    pub fn flush(&mut self) -> Result<(), LedgerError> {
+        self.out.flush()?;
+        self.out.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    // feeds the events with seq up to and including up_to, or all of them, to the projection and
+    // returns the seq of the last event applied
+    pub fn replay(&mut self, up_to: Option<u64>, projection: &mut dyn Projection) -> Result<u64, LedgerError> {
+        self.out.flush()?;
+        replay(&mut BufReader::new(File::open(&self.path)?), up_to, projection)
+    }
+}
+
+// replays an event log read from rdr, see EventLog::replay
+pub fn replay(rdr: &mut dyn BufRead, up_to: Option<u64>, projection: &mut dyn Projection) -> Result<u64, LedgerError> {
+    let mut seq = 0;
+    for line in rdr.lines() {
+        let line = line?;
+        let event: Event = serde_json::from_str(&line)
+            .map_err(|e| LedgerError::Storage { reason: format!("unreadable event after seq:[{}]: {}", seq, e) })?;
+        if up_to.is_some_and(|up_to| event.seq > up_to) {
+            break;
+        }
+        if event.seq != seq + 1 {
+            return Err(LedgerError::Storage { reason: format!("event seq:[{}] follows seq:[{}]", event.seq, seq) });
+        }
+        projection.apply(&event)?;
+        seq = event.seq;
+    }
+    Ok(seq)
+}
+
+// ledger whose state is only ever derived from its event log. every accepted transaction is appended to the
+// log with its outcome, on open the log is replayed to rebuild the state, so the log is the single source of truth
+pub struct EventSourcedLedger {
+    log: EventLog,
+    state: InMemoryLedger,
+}
+
+impl EventSourcedLedger {
+    pub fn open(path: &Path) -> Result<Self, LedgerError> {
+        let mut log = EventLog::open(path)?;
+        let mut state = InMemoryLedger::default();
+        log.replay(None, &mut state)?;
+        Ok(Self { log, state })
+    }
+
+    pub fn log(&mut self) -> &mut EventLog {
+        &mut self.log
+    }
+
+    // the full ledger as it was right after event seq
+    pub fn state_at(&mut self, seq: u64) -> Result<InMemoryLedger, LedgerError> {
+        let mut state = InMemoryLedger::default();
+        self.log.replay(Some(seq), &mut state)?;
+        Ok(state)
+    }
+
+    pub fn set_journal(&mut self, journal: Box<dyn JournalSink>) {
+        self.state.set_journal(journal);
+    }
+
+    pub fn take_journal(&mut self) -> Option<Box<dyn JournalSink>> {
+        self.state.take_journal()
+    }
+
+    pub fn set_audit(&mut self, policy: AuditPolicy) {
+        self.state.set_audit(policy);
+    }
+
+    pub fn set_locked_policy(&mut self, policy: LockedAccountPolicy) {
+        self.state.set_locked_policy(policy);
+    }
+
+    pub fn set_authorization_expiry(&mut self, transactions: u64) {
+        self.state.set_authorization_expiry(transactions);
+    }
+
+    // a refused capture or void can still have released the expired authorization it named, it is logged with its reason
+    // so replay refuses it the same way
+    fn record_outcome(&mut self, trans: &Transaction, result: &Result<(), LedgerError>) -> Result<(), LedgerError> {
+        match result {
+            Ok(()) => self.append_outcome(trans, None),
+            Err(e) if !self.state.expired().is_empty() => self.append_outcome(trans, Some(e.code())),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn append_outcome(&mut self, trans: &Transaction, refused: Option<&str>) -> Result<(), LedgerError> {
+        let state = &self.state;
+        if let Some(cas) = state.account_of(trans) {
+            let authorization = match trans.transaction_type {
+                TransactionType::Authorize => trans.tx.and_then(|tid| state.by_transaction_id.get(&tid)).and_then(|r| r.authorization.as_ref()),
+                _ => None,
+            };
+            self.log.append(trans.line, trans, cas, state.counterparty_of(trans), authorization, refused)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EventSourcedLedger {
+    fn drop(&mut self) {
+        let _ = self.log.flush();
+    }
+}
+
+impl Ledger for EventSourcedLedger {
+    fn process_transaction(&mut self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
+        let result = self.state.process_transaction(verbose, trans);
+        self.record_outcome(trans, &result)?;
+        result
+    }
+
+    fn process_admin(&mut self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
+        let result = self.state.process_admin(verbose, trans);
+        self.record_outcome(trans, &result)?;
+        result
+    }
+
+    fn get_funds_available(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        self.state.get_funds_available(client_id)
+    }
+
+    fn get_funds_held(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        self.state.get_funds_held(client_id)
+    }
+
+    fn get_funds_total(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        self.state.get_funds_total(client_id)
+    }
+
+    fn verify_transaction(&self, trans: &Transaction) -> Result<(), LedgerError> {
+        self.state.verify_transaction(trans)
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = &AccountStatus> + '_> {
+        self.state.accounts()
+    }
+
+    fn account_of(&self, trans: &Transaction) -> Option<&AccountStatus> {
+        self.state.account_of(trans)
+    }
+
+    fn counterparty_of(&self, trans: &Transaction) -> Option<&AccountStatus> {
+        self.state.counterparty_of(trans)
+    }
+
+    fn trial_balance(&self) -> TrialBalance {
+        self.state.trial_balance()
+    }
+
+    fn save_snapshot(&self, out: &mut dyn Write) -> Result<(), LedgerError> {
+        self.state.save_snapshot(out)
+    }
+
+    // state that did not come from the log could never be rebuilt from it
+    fn load_snapshot(&mut self, _rdr: &mut dyn Read) -> Result<(), LedgerError> {
+        Err(LedgerError::Storage { reason: "an event sourced ledger can only be restored from its event log".to_string() })
+    }
+}
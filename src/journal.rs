@@ -0,0 +1,146 @@
+use crate::AccountStatus;
+use crate::error::JournalError;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::Sender;
+
+// what happened to an account, a chargeback is journaled as a reversal followed by a lock. operator
+// adjustments are journaled as credits and debits carrying the operator's reason. an authorization that
+// runs out is journaled as an expire under its own tx, after the transaction that triggered it
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalEvent {
+    Credit,
+    Debit,
+    Hold,
+    Release,
+    Reversal,
+    Lock,
+    Unlock,
+    Authorize,
+    Capture,
+    Void,
+    Expire,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Balances {
+    pub available: Decimal,
+    pub held: Decimal,
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    pub authorized: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl Balances {
+    pub fn of(cas: &AccountStatus) -> Self {
+        Self { available: cas.available, held: cas.held, authorized: cas.authorized, total: cas.total, locked: cas.locked }
+    }
+}
+
+// one balance change, seq counts every entry of a run from 1 so gaps or reordering are visible.
+// line is the input line of the transaction that caused the change
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub line: Option<u64>,
+    pub client: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset: Option<String>,
+    pub tx: u32,
+    pub event: JournalEvent,
+    pub amount: Decimal,
+    pub before: Balances,
+    pub after: Balances,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+// receives journal entries as the ledger applies transactions, entries are never changed once recorded
+pub trait JournalSink: Send {
+    fn record(&mut self, entry: JournalEntry) -> io::Result<()>;
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// hands entries to whoever holds the receiving end, recording fails once the receiver is gone
+impl JournalSink for Sender<JournalEntry> {
+    fn record(&mut self, entry: JournalEntry) -> io::Result<()> {
+        self.send(entry).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "journal receiver dropped"))
+    }
+}
+
+// prev_hash of the first entry in a journal
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// a journal line, the entry fields followed by the hash of the line before it and the hash of this line
+#[derive(Debug, Serialize, Deserialize)]
+struct ChainedEntry {
+    #[serde(flatten)]
+    entry: JournalEntry,
+    prev_hash: String,
+    hash: String,
+}
+
+// sha256 over the previous hash and the json of the entry, both as written to the journal, in lowercase hex
+pub fn chain_hash(prev_hash: &str, entry: &JournalEntry) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(prev_hash.as_bytes());
+    hasher.update(serde_json::to_vec(entry)?);
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// one json object per line so the journal can be appended to and read back a line at a time. every
+// line carries a hash over its own contents and the hash of the line before, so changing, dropping or
+// reordering any line breaks the chain from that point on
+pub struct JournalWriter {
+    out: Box<dyn Write + Send>,
+    prev_hash: String,
+}
+
+impl JournalWriter {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self { out, prev_hash: GENESIS_HASH.to_string() }
+    }
+}
+
+impl JournalSink for JournalWriter {
+    fn record(&mut self, entry: JournalEntry) -> io::Result<()> {
+        let hash = chain_hash(&self.prev_hash, &entry)?;
+        let prev_hash = std::mem::replace(&mut self.prev_hash, hash.clone());
+        serde_json::to_writer(&mut self.out, &ChainedEntry { entry, prev_hash, hash })?;
+        self.out.write_all(b"\n")
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// recomputes the chain of a journal written by JournalWriter and returns the number of entries,
+// or the first line whose link does not hold
+pub fn verify_journal(rdr: &mut dyn BufRead) -> Result<u64, JournalError> {
+    let mut prev_hash = GENESIS_HASH.to_string();
+    let mut count = 0;
+    for (idx, line) in rdr.lines().enumerate() {
+        let line = line?;
+        let line_number = idx as u64 + 1;
+        let chained: ChainedEntry = serde_json::from_str(&line)
+            .map_err(|e| JournalError::Malformed { line: line_number, reason: e.to_string() })?;
+        let seq = chained.entry.seq;
+        if chained.prev_hash != prev_hash {
+            return Err(JournalError::PrevHashMismatch { line: line_number, seq, expected: prev_hash, found: chained.prev_hash });
+        }
+        let hash = chain_hash(&prev_hash, &chained.entry)?;
+        if chained.hash != hash {
+            return Err(JournalError::HashMismatch { line: line_number, seq, expected: hash, found: chained.hash });
+        }
+        prev_hash = hash;
+        count += 1;
+    }
+    Ok(count)
+}
@@ -1,422 +1,872 @@
-use crate::{AccountStatus, Ledger, Transaction, TransactionType};
-use std::collections::{HashMap,hash_map::Entry::{Occupied,Vacant}};
-use std::{error::Error};
+use crate::{AccountKey, AccountStatus, Authorization, AuthorizationState, DisputeState, Ledger, Transaction, TransactionRecord, TransactionType};
+use std::collections::{BTreeMap, HashMap, btree_map::Entry as ClientEntry, hash_map::Entry::{Occupied,Vacant}};
+use crate::audit::{Audit, AuditPolicy};
+use crate::error::LedgerError;
+use crate::policy::LockedAccountPolicy;
+use crate::journal::{Balances, JournalEntry, JournalEvent, JournalSink};
+use crate::postings::{LedgerAccount, Posting, PostingBook, TrialBalance};
+use crate::snapshot;
 use rust_decimal::Decimal;
-use std::collections::BTreeMap;
 
-const ILLEGAL_STATE: &'static str = "Illegal state error";
-
-#[derive(Debug)]
+#[derive(Default)]
 pub struct InMemoryLedger {
-    pub by_client_id: HashMap<u16, AccountStatus>,
-    pub by_transaction_id: HashMap<u32, Transaction>
+    pub by_account: BTreeMap<AccountKey, AccountStatus>, // kept sorted so the report comes out ordered by client id and asset
+    pub by_transaction_id: HashMap<u32, TransactionRecord>,
+    book: PostingBook, // every balance change is also posted here between two accounts
+    journal: Option<Box<dyn JournalSink>>,
+    journal_seq: u64,
+    audit: Option<Audit>,
+    locked_policy: LockedAccountPolicy,
+    authorization_expiry: Option<u64>, // later transactions on the account an authorization stays open for
+    open_authorizations: BTreeMap<AccountKey, Vec<u32>>,
+    expired: Vec<u32> // authorizations the last accepted transaction expired
 }
 
-impl Default for InMemoryLedger {
-    fn default() -> Self {
-        Self {
-            by_client_id: HashMap::new(),
-            by_transaction_id: HashMap::new()
-        }
+// an account with its open authorizations and their records, lent by the shard that owns it to the shard
+// applying a transaction that reaches across shards and given back after
+pub struct AccountLoan {
+    key: AccountKey,
+    account: Option<AccountStatus>,
+    authorizations: Option<Vec<u32>>,
+    records: Vec<(u32, Option<TransactionRecord>)>,
+}
+
+impl AccountLoan {
+    pub fn new(key: AccountKey) -> Self {
+        Self { key, account: None, authorizations: None, records: Vec::new() }
     }
 }
 
 impl InMemoryLedger {
 
-    fn create_empty_accountstatus(client_id: u16) -> AccountStatus {
-        let nas = AccountStatus { 
-            client: client_id, 
-            available: Decimal::new(0,0), 
-            held: Decimal::new(0,0), locked: false, 
-            total: Decimal::new(0,0)};
-        return nas;
+    // every balance change from now on is recorded to the journal, sequence numbers restart at 1
+    pub fn set_journal(&mut self, journal: Box<dyn JournalSink>) {
+        self.journal = Some(journal);
+        self.journal_seq = 0;
+    }
+
+    pub fn take_journal(&mut self) -> Option<Box<dyn JournalSink>> {
+        self.journal.take()
+    }
+
+    pub fn set_locked_policy(&mut self, policy: LockedAccountPolicy) {
+        self.locked_policy = policy;
+    }
+
+    // authorizations made from now on are released once the account has accepted transactions more transactions
+    pub fn set_authorization_expiry(&mut self, transactions: u64) {
+        self.authorization_expiry = Some(transactions);
+    }
+
+    // moves the account of loan out of this ledger, along with its open authorizations and the records of
+    // those and of any authorization the loan already carried, which may have expired in the meantime
+    pub fn lend(&mut self, loan: &mut AccountLoan) {
+        loan.account = self.by_account.remove(&loan.key);
+        loan.authorizations = self.open_authorizations.remove(&loan.key);
+        for tid in loan.authorizations.iter().flatten() {
+            if !loan.records.iter().any(|(t, _)| t == tid) {
+                loan.records.push((*tid, None));
+            }
+        }
+        for (tid, record) in loan.records.iter_mut() {
+            *record = self.by_transaction_id.remove(tid);
+        }
+    }
+
+    pub fn receive(&mut self, loan: &mut AccountLoan) {
+        if let Some(cas) = loan.account.take() {
+            self.by_account.insert(loan.key.clone(), cas);
+        }
+        if let Some(authorizations) = loan.authorizations.take() {
+            self.open_authorizations.insert(loan.key.clone(), authorizations);
+        }
+        for (tid, record) in loan.records.iter_mut() {
+            if let Some(record) = record.take() {
+                self.by_transaction_id.insert(*tid, record);
+            }
+        }
+    }
+
+    // the stored authorizations the last transaction released by expiry, they changed alongside its account.
+    // a refused capture or void can release the authorization it names, so this is set for those too
+    pub fn expired(&self) -> &[u32] {
+        &self.expired
+    }
+
+    // applies a transaction that was accepted before, e.g. from an event log. it passed the locked account policy
+    // of its run then, so it is not held back by the policy of this one
+    pub fn replay_transaction(&mut self, trans: &Transaction) -> Result<(), LedgerError> {
+        let policy = std::mem::replace(&mut self.locked_policy, LockedAccountPolicy::allowing_all());
+        let result = self.apply(false, trans, trans.transaction_type.is_admin());
+        self.locked_policy = policy;
+        result
+    }
+
+    // from now on the ledger invariants are checked after every accepted transaction
+    pub fn set_audit(&mut self, policy: AuditPolicy) {
+        self.audit = Some(Audit::new(policy, self.by_transaction_id.values()));
+    }
+
+    // counterparty is the second account trans changed with its balances before, see counterparty_key
+    fn audit_transaction(&mut self, trans: &Transaction, key: &AccountKey, before: Option<&Balances>, counterparty: Option<(&AccountKey, Option<&Balances>)>, admin: bool) -> Result<(), LedgerError> {
+        let audit = match self.audit.as_mut() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let allowed_when_locked = admin || self.locked_policy.allows(trans);
+        if let Some(after) = self.by_account.get(key) {
+            let records = &self.by_transaction_id;
+            let record = trans.tx.and_then(|tid| records.get(&tid));
+            audit.check(trans, record, before, after, allowed_when_locked)?;
+        }
+        if let Some((key, before)) = counterparty {
+            if let Some(after) = self.by_account.get(key) {
+                audit.check_account(trans, before, after, allowed_when_locked)?;
+            }
+        }
+        Ok(())
+    }
+
+    // needed after the account and transaction maps were filled directly instead of by processing transactions,
+    // also finds the authorizations that are still open
+    pub fn rebuild_postings(&mut self) {
+        self.book = PostingBook::from_state(self.by_account.values(), self.by_transaction_id.values());
+        self.index_authorizations();
+    }
+
+    fn index_authorizations(&mut self) {
+        self.open_authorizations.clear();
+        let mut open: Vec<(&u32, &TransactionRecord)> = self.by_transaction_id.iter()
+            .filter(|(_, r)| r.authorization.as_ref().is_some_and(|a| a.state == AuthorizationState::Open))
+            .collect();
+        open.sort_by_key(|(tid, _)| **tid);
+        for (tid, record) in open {
+            let key = (record.transaction.client.unwrap_or_default(), record.transaction.asset.clone());
+            self.open_authorizations.entry(key).or_default().push(*tid);
+        }
+    }
+
+    fn post(&mut self, tx: u32, asset: Option<String>, debit: LedgerAccount, credit: LedgerAccount, amount: Decimal) {
+        self.book.post(&Posting { tx, asset, debit, credit, amount });
+    }
+
+    // the client's account in the asset of trans. disputes, resolves and chargebacks go to the account of the
+    // transaction they refer to whether or not the row names its asset, for a transfer that is the destination's
+    pub(crate) fn account_key(&self, trans: &Transaction) -> Option<AccountKey> {
+        let cid = trans.client?;
+        match self.referenced_record(trans) {
+            Some(record) => Some((Self::holder_of(record), record.transaction.asset.clone())),
+            None => Some((cid, trans.asset.clone())),
+        }
+    }
+
+    // the source's account when trans is a transfer or the chargeback of one
+    pub(crate) fn counterparty_key(&self, trans: &Transaction) -> Option<AccountKey> {
+        match trans.transaction_type {
+            TransactionType::Transfer => Some((trans.destination?, trans.asset.clone())),
+            TransactionType::Chargeback => self.referenced_record(trans)
+                .filter(|r| r.transaction.transaction_type == TransactionType::Transfer)
+                .map(|r| (r.transaction.client.unwrap_or_default(), r.transaction.asset.clone())),
+            _ => None,
+        }
+    }
+
+    // the stored transaction of the same client a dispute, resolve, chargeback, capture or void refers to
+    fn referenced_record(&self, trans: &Transaction) -> Option<&TransactionRecord> {
+        match trans.transaction_type {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+                | TransactionType::Capture | TransactionType::Void =>
+                trans.tx.and_then(|tid| self.by_transaction_id.get(&tid)).filter(|r| r.transaction.client == trans.client),
+            _ => None,
+        }
+    }
+
+    // client whose funds a dispute of record holds, the destination of a transfer has the transferred funds
+    fn holder_of(record: &TransactionRecord) -> u16 {
+        match record.transaction.transaction_type {
+            TransactionType::Transfer => record.transaction.destination.unwrap_or_default(),
+            _ => record.transaction.client.unwrap_or_default(),
+        }
+    }
+
+    // called after a transaction was applied, before is the account as it was when the transaction arrived.
+    // counterparty is set when key is the second account a transfer or its chargeback changed
+    fn journal_transaction(&mut self, trans: &Transaction, key: &AccountKey, before: Option<Balances>, counterparty: bool) -> Result<(), LedgerError> {
+        if self.journal.is_none() {
+            return Ok(());
+        }
+        let after = match self.by_account.get(key) {
+            Some(cas) => Balances::of(cas),
+            None => return Ok(()),
+        };
+        let mut before = before.unwrap_or_else(|| Balances::of(&Self::create_empty_accountstatus(key)));
+        let tid = trans.tx.unwrap_or_default();
+        // disputes, resolves, chargebacks and voids move the amount of the transaction they refer to, a capture what it captured
+        let record = self.by_transaction_id.get(&tid);
+        let amount = match trans.transaction_type {
+            TransactionType::Capture => record.and_then(|r| r.authorization.as_ref()).and_then(|a| a.captured),
+            _ => record.and_then(|r| r.transaction.amount),
+        }.unwrap_or_default();
+        let changes = match trans.transaction_type {
+            TransactionType::Transfer | TransactionType::Chargeback if counterparty => vec![(JournalEvent::Credit, after)],
+            TransactionType::Transfer => vec![(JournalEvent::Debit, after)],
+            TransactionType::Deposit | TransactionType::AdjustCredit => vec![(JournalEvent::Credit, after)],
+            TransactionType::Withdrawal | TransactionType::AdjustDebit => vec![(JournalEvent::Debit, after)],
+            TransactionType::Unlock => vec![(JournalEvent::Unlock, after)],
+            TransactionType::Authorize => vec![(JournalEvent::Authorize, after)],
+            TransactionType::Capture => vec![(JournalEvent::Capture, after)],
+            TransactionType::Void => vec![(JournalEvent::Void, after)],
+            TransactionType::Dispute => vec![(JournalEvent::Hold, after)],
+            TransactionType::Resolve => vec![(JournalEvent::Release, after)],
+            TransactionType::Chargeback => {
+                let reversed = Balances { locked: before.locked, ..after.clone() };
+                vec![(JournalEvent::Reversal, reversed), (JournalEvent::Lock, after)]
+            }
+        };
+        for (event, after) in changes {
+            let amount = if event == JournalEvent::Lock || event == JournalEvent::Unlock { Decimal::new(0,0) } else { amount };
+            self.record_journal(JournalEntry {
+                seq: 0,
+                line: trans.line,
+                client: key.0,
+                asset: key.1.clone(),
+                tx: tid,
+                event,
+                amount,
+                before,
+                after: after.clone(),
+                reason: trans.reason.clone(),
+            })?;
+            before = after;
+        }
+        Ok(())
+    }
+
+    // numbers the entry and hands it to the journal
+    fn record_journal(&mut self, mut entry: JournalEntry) -> Result<(), LedgerError> {
+        let journal = match self.journal.as_mut() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        self.journal_seq += 1;
+        entry.seq = self.journal_seq;
+        journal.record(entry)?;
+        Ok(())
+    }
+
+    fn create_empty_accountstatus(key: &AccountKey) -> AccountStatus {
+        AccountStatus {
+            client: key.0,
+            asset: key.1.clone(),
+            available: Decimal::new(0,0),
+            held: Decimal::new(0,0), locked: false,
+            authorized: Decimal::new(0,0),
+            total: Decimal::new(0,0),
+            applied: 0}
+    }
+
+    pub fn process_deposit(&mut self, trans: &Transaction) -> Result<(), LedgerError> {
+        self.credit(trans, LedgerAccount::PartnerSettlement)
+    }
+
+    // a refused withdrawal is not stored so it leaves the ledger unchanged and can not be disputed later
+    pub fn process_withdrawal(&mut self, trans: &Transaction) -> Result<(), LedgerError> {
+        self.debit(trans, LedgerAccount::PartnerSettlement)
     }
 
-    pub fn process_deposit(&mut self, trans: &Transaction) -> Result<(), Box<dyn Error>> {
+    // adds the amount to available, the funds come out of counter_account
+    fn credit(&mut self, trans: &Transaction, counter_account: LedgerAccount) -> Result<(), LedgerError> {
         let cid = match trans.client {
             Some(v) => v,
-            None => return Err("need client id from transaction".into()),
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
         };
         let tid = match trans.tx {
             Some(v) => v,
-            None => return Err("need transaction id from transaction".into()),
-        };
-        let amount = match trans.amount {
-            Some(v) => {v},
-            None => {Decimal::new(0,0)},
+            None => return Err(LedgerError::MissingTransactionId { client: Some(cid) }),
         };
+        let amount = trans.amount.unwrap_or_default();
         match self.by_transaction_id.entry(tid) {
             Occupied(_) => {
-                //let bad_trans = entry.get();
-                return Err("Duplicate transaction".into());
+                return Err(LedgerError::DuplicateTransaction { client: cid, tx: tid });
             },
             Vacant(entry) => {
-                entry.insert(trans.clone());
+                entry.insert(TransactionRecord::new(trans.clone()));
             }
         }
-        match self.by_client_id.entry(cid) {
-            Occupied(mut entry) => {
-                let mut acct_status = entry.get_mut();
-                let current_available = acct_status.available;
-                acct_status.available = current_available + amount;
+        let key = (cid, trans.asset.clone());
+        match self.by_account.entry(key) {
+            ClientEntry::Occupied(mut entry) => {
+                let acct_status = entry.get_mut();
+                acct_status.available += amount;
             },
-            Vacant(entry) => {
-                let mut acct_status = Self::create_empty_accountstatus(cid);
+            ClientEntry::Vacant(entry) => {
+                let mut acct_status = Self::create_empty_accountstatus(entry.key());
                 acct_status.available = amount;
                 entry.insert(acct_status);
             }
         }
-        return Ok(());
+        self.post(tid, trans.asset.clone(), counter_account, LedgerAccount::ClientAvailable(cid), amount);
+        Ok(())
     }
 
-    pub fn process_withdrawal(&mut self,trans: &Transaction) -> Result<(), Box<dyn Error>> {
+    // takes the amount out of available into counter_account, refused when available does not cover it
+    fn debit(&mut self, trans: &Transaction, counter_account: LedgerAccount) -> Result<(), LedgerError> {
         let cid = match trans.client {
             Some(v) => v,
-            None => return Err("need client id from transaction".into()),
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
         };
         let tid = match trans.tx {
             Some(v) => v,
-            None => return Err("need transaction id from transaction".into()),
+            None => return Err(LedgerError::MissingTransactionId { client: Some(cid) }),
         };
         let amount = match trans.amount {
             Some(v) => {v},
-            None => return Err("need amount from transaction".into()),
+            None => return Err(LedgerError::MissingAmount { client: cid, tx: tid }),
         };
-        match self.by_transaction_id.entry(tid) {
+        let entry = match self.by_transaction_id.entry(tid) {
             Occupied(_) => {
-                //let bad_trans = entry.get();
-                return Err("Duplicate transaction".into());
+                return Err(LedgerError::DuplicateTransaction { client: cid, tx: tid });
             },
-            Vacant(entry) => {
-                entry.insert(trans.clone());
-            }
-        }
-        match self.by_client_id.entry(cid) {
-            Occupied(mut entry) => {
-                let mut acct_status = entry.get_mut();
-                let current_available = acct_status.available;
-                if amount > current_available {
-                    return Err("Insufficient funds".into());
+            Vacant(entry) => entry
+        };
+        match self.by_account.entry((cid, trans.asset.clone())) {
+            ClientEntry::Occupied(mut client_entry) => {
+                let acct_status = client_entry.get_mut();
+                if amount > acct_status.available {
+                    return Err(LedgerError::InsufficientFunds { client: cid, tx: tid, amount, available: acct_status.available });
                 }
-                acct_status.available = current_available - amount;
+                acct_status.available -= amount;
             },
-            Vacant(_) => {
-                return Err("Insufficient funds, non existent by client id".into());
+            ClientEntry::Vacant(_) => {
+                return Err(LedgerError::InsufficientFunds { client: cid, tx: tid, amount, available: Decimal::new(0,0) });
             }
         }
-        return Ok(());
+        entry.insert(TransactionRecord::new(trans.clone()));
+        self.post(tid, trans.asset.clone(), LedgerAccount::ClientAvailable(cid), counter_account, amount);
+        Ok(())
     }
 
-    fn process_dispute(&mut self, verbose: bool, trans: &Transaction) -> Result<(), Box<dyn Error>> {
+    pub fn process_unlock(&mut self, trans: &Transaction) -> Result<(), LedgerError> {
         let cid = match trans.client {
             Some(v) => v,
-            None => return Err("need client id from transaction".into()),
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
         };
         let tid = match trans.tx {
             Some(v) => v,
-            None => return Err("need transaction id from transaction".into()),
+            None => return Err(LedgerError::MissingTransactionId { client: Some(cid) }),
         };
-        match self.by_transaction_id.entry(tid) {
-            Occupied(client_transaction) => {
-                let ct = client_transaction.get();
-                let clientid = match ct.client {
-                    Some(v) => v,
-                    None => 0
-                };
-                if clientid == cid {  // only proceed if transaction is for the right client id indicated in dispute
-                    match self.by_client_id.entry(cid) {
-                        Occupied(mut client_account_status) => {
-                            let mut cas = client_account_status.get_mut();
-                            let cat_amount_val = match ct.amount {
-                                Some(v) => v,
-                                None => Decimal::new(0,0),
-                            };
-                            if cat_amount_val <= cas.available {
-                                cas.available = cas.available - cat_amount_val;
-                                cas.held = cas.held + cat_amount_val;
-                                if verbose {
-                                    eprintln!("DISPUTE: Funds:[{:?}] held for client id:[{:?}]",cat_amount_val,cid);
-                                }
-                            }
-                        },
-                        Vacant(_) => {
-                            if verbose {
-                                eprintln!("DISPUTE: account status client id:[{:?}] not found",cid);
-                            }
-                        }
-                    }
-                } else {
-                    if verbose {
-                        eprintln!("DISPUTE: found transaction id:[{:?}] however not for client id:[{:?}]",tid,cid);
-                    }                    
-                }
+        if self.by_transaction_id.contains_key(&tid) {
+            return Err(LedgerError::DuplicateTransaction { client: cid, tx: tid });
+        }
+        match self.by_account.get_mut(&(cid, trans.asset.clone())) {
+            Some(cas) => cas.locked = false,
+            None => return Err(LedgerError::UnknownAccount { client: cid }),
+        }
+        self.by_transaction_id.insert(tid, TransactionRecord::new(trans.clone()));
+        Ok(())
+    }
+
+    // the debit of the source is the only step that can fail, so nothing is credited unless it went through
+    pub fn process_transfer(&mut self, trans: &Transaction) -> Result<(), LedgerError> {
+        let cid = match trans.client {
+            Some(v) => v,
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
+        };
+        let tid = trans.tx.unwrap_or_default();
+        let destination = match trans.destination {
+            Some(v) => v,
+            None => return Err(LedgerError::MissingDestination { client: cid, tx: tid }),
+        };
+        let key = (destination, trans.asset.clone());
+        if self.by_account.get(&key).is_some_and(|cas| cas.locked) && !self.locked_policy.allows(trans) {
+            return Err(LedgerError::AccountLocked { client: destination, tx: tid, transaction_type: trans.transaction_type.as_str() });
+        }
+        self.debit(trans, LedgerAccount::ClientAvailable(destination))?;
+        let amount = trans.amount.unwrap_or_default();
+        self.by_account.entry(key).or_insert_with_key(Self::create_empty_accountstatus).available += amount;
+        Ok(())
+    }
+
+    // reserves the amount out of available until it is captured, voided or expires
+    pub fn process_authorize(&mut self, trans: &Transaction) -> Result<(), LedgerError> {
+        let cid = match trans.client {
+            Some(v) => v,
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
+        };
+        let tid = trans.tx.unwrap_or_default();
+        self.debit(trans, LedgerAccount::ClientAuthorized(cid))?;
+        let key = (cid, trans.asset.clone());
+        let mut expires_after = None;
+        if let Some(cas) = self.by_account.get_mut(&key) {
+            cas.authorized += trans.amount.unwrap_or_default();
+            // counted from the applied count the authorization itself leaves behind
+            expires_after = self.authorization_expiry.map(|n| cas.applied + 1 + n);
+        }
+        if let Some(record) = self.by_transaction_id.get_mut(&tid) {
+            record.authorization = Some(Authorization { state: AuthorizationState::Open, captured: None, expires_after });
+        }
+        self.open_authorizations.entry(key).or_default().push(tid);
+        Ok(())
+    }
 
+    // looks up an open authorization of the client the way find_disputable looks up a disputable transaction
+    fn find_authorization(&mut self, cid: u16, tid: u32, trans: &Transaction) -> Result<(&mut TransactionRecord, &mut AccountStatus), LedgerError> {
+        let record = match self.by_transaction_id.get_mut(&tid) {
+            Some(v) => v,
+            None => return Err(LedgerError::UnknownTransaction { client: cid, tx: tid }),
+        };
+        if record.transaction.client != Some(cid) {
+            return Err(LedgerError::ClientMismatch { client: cid, tx: tid, owner: record.transaction.client });
+        }
+        let state = match &record.authorization {
+            Some(authorization) => authorization.state,
+            None => return Err(LedgerError::NotAuthorization { client: cid, tx: tid, transaction_type: record.transaction.transaction_type.as_str() }),
+        };
+        let owner_asset = record.transaction.asset.clone();
+        if trans.asset.is_some() && trans.asset != owner_asset {
+            return Err(LedgerError::AssetMismatch { client: cid, tx: tid, asset: trans.asset.clone().unwrap_or_default(), owner_asset });
+        }
+        if state != AuthorizationState::Open {
+            return Err(LedgerError::AuthorizationClosed { client: cid, tx: tid, state });
+        }
+        if let (Some(expires_at), Some(timestamp)) = (record.transaction.expires_at, trans.timestamp) {
+            if timestamp >= expires_at {
+                return Err(LedgerError::AuthorizationExpired { client: cid, tx: tid, expires_at });
             }
-            Vacant(_) => {
-                if verbose {
-                    eprintln!("DISPUTE: transaction id:[{:?}] for client id:[{:?}] not found",tid,cid);
-                }
+        }
+        match self.by_account.get_mut(&(cid, owner_asset)) {
+            Some(cas) => Ok((record, cas)),
+            None => Err(LedgerError::UnknownAccount { client: cid }),
+        }
+    }
+
+    fn close_authorization(&mut self, key: &AccountKey, tid: u32) {
+        if let Some(open) = self.open_authorizations.get_mut(key) {
+            open.retain(|t| *t != tid);
+            if open.is_empty() {
+                self.open_authorizations.remove(key);
             }
         }
-        return Ok(());
     }
 
-    fn process_resolve(&mut self,verbose: bool,trans: &Transaction) -> Result<(), Box<dyn Error>> {
+    // settles the captured amount, which may be less than authorized, and gives the rest back to available
+    pub fn process_capture(&mut self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
         let cid = match trans.client {
             Some(v) => v,
-            None => return Err("need client id from transaction".into()),
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
         };
         let tid = match trans.tx {
             Some(v) => v,
-            None => return Err("need transaction id from transaction".into()),
+            None => return Err(LedgerError::MissingTransactionId { client: Some(cid) }),
         };
-        match self.by_transaction_id.entry(tid) {
-            Occupied(client_transaction) => {
-                let ct = client_transaction.get();
-                let clientid = match ct.client {
-                    Some(v) => v,
-                    None => 0
-                };
-                if clientid == cid {  // only proceed if transaction is for the right client id indicated in dispute
-                    match self.by_client_id.entry(cid) {
-                        Occupied(mut client_account_status) => {
-                            let mut cas = client_account_status.get_mut();
-                            let cat_amount_val = match ct.amount {
-                                Some(v) => v,
-                                None => Decimal::new(0,0),
-                            };
-                            if cat_amount_val <= cas.held { // must have enough in help to resolve amount
-                                cas.available = cas.available + cat_amount_val;
-                                cas.held = cas.held - cat_amount_val;
-                                if verbose {
-                                    eprintln!("RESOLVE: funds:[{:?}] held for client id:[{:?}] were returned",cat_amount_val,cid);
-                                }
-                            }
-                        },
-                        Vacant(_) => {
-                            if verbose {
-                                eprintln!("RESOLVE: account status client id:[{:?}] not found",cid);
-                            }
-                        }
-                    }
-                } else {
-                    if verbose {
-                        eprintln!("RESOLVE: found transaction id:[{:?}] however not for client id:[{:?}]",tid,cid);
-                    }                    
-                }
+        let (record, cas) = self.find_authorization(cid, tid, trans)?;
+        let authorized = record.transaction.amount.unwrap_or_default();
+        let captured = trans.amount.unwrap_or(authorized);
+        if captured > authorized {
+            return Err(LedgerError::CaptureExceedsAuthorization { client: cid, tx: tid, amount: captured, authorized });
+        }
+        let released = authorized - captured;
+        cas.authorized -= authorized;
+        cas.available += released;
+        let key = cas.key();
+        if let Some(authorization) = record.authorization.as_mut() {
+            authorization.state = AuthorizationState::Captured;
+            authorization.captured = Some(captured);
+        }
+        if verbose {
+            eprintln!("CAPTURE: funds:[{:?}] captured and:[{:?}] released for client id:[{:?}]",captured,released,cid);
+        }
+        self.close_authorization(&key, tid);
+        self.post(tid, key.1.clone(), LedgerAccount::ClientAuthorized(cid), LedgerAccount::PartnerSettlement, captured);
+        self.post(tid, key.1, LedgerAccount::ClientAuthorized(cid), LedgerAccount::ClientAvailable(cid), released);
+        Ok(())
+    }
 
-            }
-            Vacant(_) => {
-                if verbose {
-                    eprintln!("RESOLVE: transaction id:[{:?}] for client id:[{:?}] not found",tid,cid);
-                }
+    pub fn process_void(&mut self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
+        let cid = match trans.client {
+            Some(v) => v,
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
+        };
+        let tid = match trans.tx {
+            Some(v) => v,
+            None => return Err(LedgerError::MissingTransactionId { client: Some(cid) }),
+        };
+        let (record, cas) = self.find_authorization(cid, tid, trans)?;
+        let authorized = record.transaction.amount.unwrap_or_default();
+        cas.authorized -= authorized;
+        cas.available += authorized;
+        let key = cas.key();
+        if let Some(authorization) = record.authorization.as_mut() {
+            authorization.state = AuthorizationState::Voided;
+        }
+        if verbose {
+            eprintln!("VOID: funds:[{:?}] released for client id:[{:?}]",authorized,cid);
+        }
+        self.close_authorization(&key, tid);
+        self.post(tid, key.1, LedgerAccount::ClientAuthorized(cid), LedgerAccount::ClientAvailable(cid), authorized);
+        Ok(())
+    }
+
+    // releases the open authorizations of the account that ran out, either because the account has accepted
+    // enough transactions since or because trans carries a timestamp past their expiry. only called once trans
+    // was accepted, so expiry follows the accepted input and replays the same way
+    fn expire_authorizations(&mut self, key: &AccountKey, trans: &Transaction) -> Result<(), LedgerError> {
+        let open = match self.open_authorizations.get(key) {
+            Some(v) => v.clone(),
+            None => return Ok(()),
+        };
+        for tid in open {
+            let applied = self.by_account.get(key).map(|cas| cas.applied).unwrap_or_default();
+            let (amount, expired) = match self.by_transaction_id.get(&tid) {
+                Some(record) => {
+                    let by_count = record.authorization.as_ref().and_then(|a| a.expires_after).is_some_and(|n| applied >= n);
+                    let by_time = matches!((record.transaction.expires_at, trans.timestamp), (Some(at), Some(now)) if now >= at);
+                    (record.transaction.amount.unwrap_or_default(), by_count || by_time)
+                },
+                None => continue,
+            };
+            if expired {
+                self.expire_authorization(key, tid, amount, trans)?;
             }
         }
-        return Ok(());
+        Ok(())
     }
 
-    fn process_chargeback(&mut self,verbose: bool, trans: &Transaction) -> Result<(), Box<dyn Error>> {
+    // gives the funds of the open authorization tid back to available, trans is what noticed it ran out
+    fn expire_authorization(&mut self, key: &AccountKey, tid: u32, amount: Decimal, trans: &Transaction) -> Result<(), LedgerError> {
+        let cas = match self.by_account.get_mut(key) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let before = Balances::of(cas);
+        cas.authorized -= amount;
+        cas.available += amount;
+        cas.total = cas.available + cas.held + cas.authorized;
+        let after = Balances::of(cas);
+        if let Some(authorization) = self.by_transaction_id.get_mut(&tid).and_then(|r| r.authorization.as_mut()) {
+            authorization.state = AuthorizationState::Expired;
+        }
+        self.close_authorization(key, tid);
+        self.expired.push(tid);
+        if let Some(audit) = self.audit.as_mut() {
+            audit.expire(key, amount);
+        }
+        self.post(tid, key.1.clone(), LedgerAccount::ClientAuthorized(key.0), LedgerAccount::ClientAvailable(key.0), amount);
+        self.record_journal(JournalEntry {
+            seq: 0,
+            line: trans.line,
+            client: key.0,
+            asset: key.1.clone(),
+            tx: tid,
+            event: JournalEvent::Expire,
+            amount,
+            before,
+            after,
+            reason: None,
+        })
+    }
+
+    // looks up the referenced transaction and owning account, fails with an error the spec says to ignore
+    // as a partner side error (unknown tx, tx for a different client or asset, no account)
+    fn find_disputable(&mut self, cid: u16, tid: u32, asset: Option<&str>) -> Result<(&mut TransactionRecord, &mut AccountStatus), LedgerError> {
+        let record = match self.by_transaction_id.get_mut(&tid) {
+            Some(v) => v,
+            None => return Err(LedgerError::UnknownTransaction { client: cid, tx: tid }),
+        };
+        if record.transaction.client != Some(cid) { // only proceed if transaction is for the right client id indicated in dispute
+            return Err(LedgerError::ClientMismatch { client: cid, tx: tid, owner: record.transaction.client });
+        }
+        if !matches!(record.transaction.transaction_type, TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer) {
+            return Err(LedgerError::NotDisputable { client: cid, tx: tid, transaction_type: record.transaction.transaction_type.as_str() });
+        }
+        let owner_asset = record.transaction.asset.clone();
+        if asset.is_some() && asset != owner_asset.as_deref() {
+            return Err(LedgerError::AssetMismatch { client: cid, tx: tid, asset: asset.unwrap_or_default().to_string(), owner_asset });
+        }
+        let holder = Self::holder_of(record);
+        match self.by_account.get_mut(&(holder, owner_asset)) {
+            Some(cas) => Ok((record, cas)),
+            None => Err(LedgerError::UnknownAccount { client: holder }),
+        }
+    }
+
+    pub fn process_dispute(&mut self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
         let cid = match trans.client {
             Some(v) => v,
-            None => return Err("need client id from transaction".into()),
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
         };
         let tid = match trans.tx {
             Some(v) => v,
-            None => return Err("need transaction id from transaction".into()),
+            None => return Err(LedgerError::MissingTransactionId { client: Some(cid) }),
         };
-        match self.by_transaction_id.entry(tid) {
-            Occupied(client_transaction) => {
-                let ct = client_transaction.get();
-                let clientid = match ct.client {
-                    Some(v) => v,
-                    None => 0
-                };
-                if clientid == cid {  // only proceed if transaction is for the right client id indicated in dispute
-                    match self.by_client_id.entry(cid) {
-                        Occupied(mut client_account_status) => {
-                            let mut cas = client_account_status.get_mut();
-                            let cat_amount_val = match ct.amount {
-                                Some(v) => v,
-                                None => Decimal::new(0,0),
-                            };
-                            if cat_amount_val <= cas.available { // must have enough in available to chargeback amount (i.e. withdraw from account)
-                                cas.available = cas.available - cat_amount_val;
-                                cas.locked = true; // always freeze account after chargeback 
-                                if verbose {
-                                    eprintln!("CHARGEBACK: funds:[{:?}] withdrawn for client id:[{:?}]",cat_amount_val,cid);
-                                }
-                            }
-                        },
-                        Vacant(_) => {
-                            if verbose {
-                                eprintln!("CHARGEBACK: account status client id:[{:?}] not found",cid);
-                            }
-                        }
-                    }
-                } else {
-                    if verbose {
-                        eprintln!("CHARGEBACK: found transaction id:[{:?}] however not for client id:[{:?}]",tid,cid);
-                    }                    
-                }
+        let (record, cas) = self.find_disputable(cid, tid, trans.asset.as_deref())?;
+        let asset = record.transaction.asset.clone();
+        let holder = cas.client;
+        let next_state = record.state.transition(tid, DisputeState::Disputed)?;
+        let cat_amount_val = record.transaction.amount.unwrap_or_default();
+        if cat_amount_val > cas.available {
+            return Err(LedgerError::InsufficientFunds { client: holder, tx: tid, amount: cat_amount_val, available: cas.available });
+        }
+        cas.available -= cat_amount_val;
+        cas.held += cat_amount_val;
+        record.state = next_state;
+        if verbose {
+            eprintln!("DISPUTE: Funds:[{:?}] held for client id:[{:?}]",cat_amount_val,holder);
+        }
+        self.post(tid, asset, LedgerAccount::ClientAvailable(holder), LedgerAccount::ClientHeld(holder), cat_amount_val);
+        Ok(())
+    }
 
-            }
-            Vacant(_) => {
-                if verbose {
-                    eprintln!("CHARGEBACK: transaction id:[{:?}] for client id:[{:?}] not found",tid,cid);
+    pub fn process_resolve(&mut self,verbose: bool,trans: &Transaction) -> Result<(), LedgerError> {
+        let cid = match trans.client {
+            Some(v) => v,
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
+        };
+        let tid = match trans.tx {
+            Some(v) => v,
+            None => return Err(LedgerError::MissingTransactionId { client: Some(cid) }),
+        };
+        let (record, cas) = self.find_disputable(cid, tid, trans.asset.as_deref())?;
+        let asset = record.transaction.asset.clone();
+        let holder = cas.client;
+        record.state = record.state.transition(tid, DisputeState::Resolved)?;
+        let cat_amount_val = record.transaction.amount.unwrap_or_default();
+        cas.available += cat_amount_val;
+        cas.held -= cat_amount_val;
+        if verbose {
+            eprintln!("RESOLVE: funds:[{:?}] held for client id:[{:?}] were returned",cat_amount_val,holder);
+        }
+        self.post(tid, asset, LedgerAccount::ClientHeld(holder), LedgerAccount::ClientAvailable(holder), cat_amount_val);
+        Ok(())
+    }
+
+    pub fn process_chargeback(&mut self,verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
+        let cid = match trans.client {
+            Some(v) => v,
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
+        };
+        let tid = match trans.tx {
+            Some(v) => v,
+            None => return Err(LedgerError::MissingTransactionId { client: Some(cid) }),
+        };
+        // a charged back transfer goes back to its source instead of being lost
+        let source = match self.counterparty_key(trans) {
+            Some(key) if self.by_account.contains_key(&key) => Some(key),
+            Some(_) => return Err(LedgerError::UnknownAccount { client: cid }),
+            None => None,
+        };
+        let (record, cas) = self.find_disputable(cid, tid, trans.asset.as_deref())?;
+        let asset = record.transaction.asset.clone();
+        let holder = cas.client;
+        record.state = record.state.transition(tid, DisputeState::ChargedBack)?;
+        let cat_amount_val = record.transaction.amount.unwrap_or_default();
+        cas.held -= cat_amount_val; // held funds are withdrawn, available was already reduced by the dispute
+        cas.locked = true; // always freeze account after chargeback
+        if verbose {
+            eprintln!("CHARGEBACK: funds:[{:?}] withdrawn for client id:[{:?}]",cat_amount_val,holder);
+        }
+        let to = match source {
+            Some(key) => {
+                let to = LedgerAccount::ClientAvailable(key.0);
+                if let Some(source) = self.by_account.get_mut(&key) {
+                    source.available += cat_amount_val;
                 }
+                to
             }
-        }
-        return Ok(());
+            None => LedgerAccount::ChargebackLosses,
+        };
+        self.post(tid, asset, LedgerAccount::ClientHeld(holder), to, cat_amount_val);
+        Ok(())
     }
-}
 
-impl Ledger for InMemoryLedger {
-    fn process_transaction(&mut self,verbose: bool, trans: &Transaction) -> Result<(), Box<dyn Error>> {
+    // operator changes go through the same path as partner transactions so they are audited, journaled and
+    // posted alike, but they are not held back by the locked account policy
+    fn apply(&mut self, verbose: bool, trans: &Transaction, admin: bool) -> Result<(), LedgerError> {
+        self.expired.clear();
         let cid = match trans.client {
             Some(v) => v,
-            None => return Err("need client id from transaction".into()),
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
         };
-        let account_status = self.by_client_id.get(&cid);
+        if trans.transaction_type.is_admin() != admin {
+            let tx = trans.tx.unwrap_or_default();
+            let transaction_type = trans.transaction_type.as_str();
+            return Err(if admin { LedgerError::NotAdmin { client: cid, tx, transaction_type } } else { LedgerError::AdminOnly { client: cid, tx, transaction_type } });
+        }
+        let key = self.account_key(trans).unwrap_or((cid, None));
+        let counterparty = self.counterparty_key(trans);
+        let counterparty_before = counterparty.as_ref().and_then(|k| self.by_account.get(k)).map(Balances::of);
+        let account_status = self.by_account.get(&key);
+        let before = account_status.map(Balances::of);
         if verbose {
             eprintln!("incomming transaction:[{:?}] available:[{:?}]",trans,account_status);
         }
-        match account_status {
-            Some(cas) => {
-                if cas.locked {
-                    if verbose {
-                        eprintln!("client account:[{:?}] locked skipping transaction",cid);
-                    }
-                    return Ok(()); 
+        // operators only ever correct accounts that already exist
+        if admin && account_status.is_none() {
+            return Err(LedgerError::UnknownAccount { client: cid });
+        }
+        // do nothing if account status for client is not found since account status could be created with transaction processing
+        if let Some(cas) = account_status {
+            if cas.locked && !admin && !self.locked_policy.allows(trans) {
+                return Err(LedgerError::AccountLocked { client: cid, tx: trans.tx.unwrap_or_default(), transaction_type: trans.transaction_type.as_str() });
+            }
+        }
+        self.verify_transaction(trans)?;
+        match trans.transaction_type {
+            TransactionType::Deposit => self.process_deposit(trans)?,
+            TransactionType::Withdrawal => self.process_withdrawal(trans)?,
+            TransactionType::Dispute => self.process_dispute(verbose,trans)?,
+            TransactionType::Resolve => self.process_resolve(verbose,trans)?,
+            TransactionType::Chargeback => self.process_chargeback(verbose,trans)?,
+            TransactionType::Transfer => self.process_transfer(trans)?,
+            TransactionType::Authorize => self.process_authorize(trans)?,
+            TransactionType::Capture | TransactionType::Void => {
+                let result = if trans.transaction_type == TransactionType::Capture { self.process_capture(verbose,trans) } else { self.process_void(verbose,trans) };
+                // the row is refused but the authorization it found past its expiry is released all the same,
+                // so a later row without a timestamp can not capture it
+                if let Err(LedgerError::AuthorizationExpired { tx, .. }) = result {
+                    let amount = self.by_transaction_id.get(&tx).and_then(|r| r.transaction.amount).unwrap_or_default();
+                    self.expire_authorization(&key, tx, amount, trans)?;
                 }
+                result?
             },
-            None => {} // do nothing if account status for client is not found since account status could be created with transaction processing
+            TransactionType::Unlock => self.process_unlock(trans)?,
+            TransactionType::AdjustCredit => self.credit(trans, LedgerAccount::OperatorAdjustments)?,
+            TransactionType::AdjustDebit => self.debit(trans, LedgerAccount::OperatorAdjustments)?,
         };
-        Self::verify_transaction(&self,trans).unwrap();
-        match trans.transaction_type {
-            TransactionType::Deposit => {
-                self.process_deposit(trans)?;
-            },
-            TransactionType::Withdrawal => {
-                match self.process_withdrawal(trans) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        if verbose {
-                            eprintln!("skipping withdrawal transaction reason:[{}]",e);
-                        }
-                    }
-                } 
-            },
-            TransactionType::Dispute => {
-                self.process_dispute(verbose,trans)?; 
-            },
-            TransactionType::Resolve => {
-                self.process_resolve(verbose,trans)?; 
+        for k in std::iter::once(&key).chain(counterparty.as_ref()) {
+            if let Some(cas) = self.by_account.get_mut(k) {
+                cas.total = cas.available + cas.held + cas.authorized;
             }
-            TransactionType::Chargeback => {
-                self.process_chargeback(verbose,trans)?; 
-            },
-        };
-        let new_account_status = self.by_client_id.get(&cid);
+        }
+        if let Some(cas) = self.by_account.get_mut(&key) {
+            cas.applied += 1;
+        }
+        self.audit_transaction(trans, &key, before.as_ref(), counterparty.as_ref().map(|k| (k, counterparty_before.as_ref())), admin)?;
+        self.journal_transaction(trans, &key, before, false)?;
+        if let Some(k) = &counterparty {
+            self.journal_transaction(trans, k, counterparty_before, true)?;
+        }
+        self.expire_authorizations(&key, trans)?;
+        let new_account_status = self.by_account.get(&key);
         if verbose {
             eprintln!("after transaction:[{:?}] available:[{:?}]",trans,new_account_status);
         }        
-        return Ok(());
+        Ok(())
+    }
+}
+
+impl Ledger for InMemoryLedger {
+    fn process_transaction(&mut self,verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
+        self.apply(verbose, trans, false)
+    }
+
+    fn process_admin(&mut self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
+        self.apply(verbose, trans, true)
     }
 
-    fn get_funds_available(&self, client_id: u16) -> Result<Decimal, Box<dyn Error>> {
-        let available = match self.by_client_id.get(&client_id) {
+    fn get_funds_available(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        let available = match self.by_account.get(&(client_id, None)) {
             Some(v) => v.available,
-            None => { return Err("account status not initialized".into())}
+            None => { return Err(LedgerError::UnknownAccount { client: client_id })}
         };
-        return Ok(available)
+        Ok(available)
     }
     
-    fn get_funds_held(&self, client_id: u16) -> Result<Decimal, Box<dyn Error>> {
-        let available = match self.by_client_id.get(&client_id) {
-            Some(v) => v.available,
-            None => { return Err("account status not initialized".into())}
+    fn get_funds_held(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        let held = match self.by_account.get(&(client_id, None)) {
+            Some(v) => v.held,
+            None => { return Err(LedgerError::UnknownAccount { client: client_id })}
         };
-        return Ok(available)
+        Ok(held)
     }
 
-    fn get_funds_total(&self, client_id: u16) -> Result<Decimal, Box<dyn Error>> {
-        let cas = match self.by_client_id.get(&client_id) {
+    fn get_funds_total(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        let cas = match self.by_account.get(&(client_id, None)) {
             Some(v) => v,
-            None => { return Err("account status not initialized".into())}
+            None => { return Err(LedgerError::UnknownAccount { client: client_id })}
         };
-        let total = cas.available + cas.held;
-        return Ok(total);
+        let total = cas.available + cas.held + cas.authorized;
+        Ok(total)
     }
     
-    // fn get_all_clients(&self) -> Result<HashMap<u16, AccountStatus>, Box<dyn Error>> {
-    //     return Ok(self.by_client_id);
+    // fn get_all_clients(&self) -> Result<HashMap<u16, AccountStatus>, LedgerError> {
+    //     return Ok(self.by_account);
     // }
 
-    fn verify_transaction(&self, trans: &Transaction) -> Result<(), Box<dyn Error>> {
-        if trans.client == None || trans.tx == None {
-            return Err(ILLEGAL_STATE.into());
+    fn verify_transaction(&self, trans: &Transaction) -> Result<(), LedgerError> {
+        let cid = match trans.client {
+            Some(v) => v,
+            None => return Err(LedgerError::MissingClient { tx: trans.tx }),
+        };
+        let tid = match trans.tx {
+            Some(v) => v,
+            None => return Err(LedgerError::MissingTransactionId { client: Some(cid) }),
+        };
+        if trans.transaction_type.is_admin() && trans.reason.as_deref().unwrap_or_default().trim().is_empty() {
+            return Err(LedgerError::MissingReason { client: cid, tx: tid });
         }
         if trans.transaction_type == TransactionType::Dispute
             || trans.transaction_type == TransactionType::Resolve
-            || trans.transaction_type == TransactionType::Chargeback {
-            if trans.amount != None {
-                return Err(ILLEGAL_STATE.into());
+            || trans.transaction_type == TransactionType::Chargeback
+            || trans.transaction_type == TransactionType::Unlock
+            || trans.transaction_type == TransactionType::Void {
+            if let Some(amount) = trans.amount {
+                return Err(LedgerError::UnexpectedAmount { client: cid, tx: tid, amount });
             }
-        } else {
-            if trans.amount == None {
-                return Err(ILLEGAL_STATE.into());
+        } else if trans.amount.is_none() && trans.transaction_type != TransactionType::Capture { // a capture without amount takes all of it
+            return Err(LedgerError::MissingAmount { client: cid, tx: tid });
+        }
+        // a negative transfer would pull funds out of the destination, a negative authorization or capture
+        // would create funds and a negative adjustment would move them the other way. a capture of zero only
+        // gives the authorization back
+        let invalid = |amount: &Decimal| match trans.transaction_type {
+            TransactionType::Transfer | TransactionType::Authorize
+                | TransactionType::AdjustCredit | TransactionType::AdjustDebit => *amount <= Decimal::new(0,0),
+            TransactionType::Capture => *amount < Decimal::new(0,0),
+            _ => false,
+        };
+        if let Some(amount) = trans.amount.filter(invalid) {
+            return Err(LedgerError::InvalidAmount { client: cid, tx: tid, amount, transaction_type: trans.transaction_type.as_str() });
+        }
+        if trans.transaction_type == TransactionType::Transfer {
+            match trans.destination {
+                None => return Err(LedgerError::MissingDestination { client: cid, tx: tid }),
+                Some(destination) if destination == cid => return Err(LedgerError::SelfTransfer { client: cid, tx: tid }),
+                Some(_) => (),
             }
         }
         Ok(())
     }
 
-    fn read_transactions(&mut self, verbose: bool, withdraw_deposits: BTreeMap<u32, Transaction>, disputes: Vec<Transaction>, resolves: Vec<Transaction>, chargebacks: Vec<Transaction>) {
-        for (txid, transaction) in withdraw_deposits {
-            self.process_transaction(verbose, &transaction).unwrap();
-            if verbose {
-                eprintln!("processed withdraw or deposit txid:[{:?}] transaction:[{:?}]",txid,transaction);
-            }
-        }
-        for transaction in disputes {
-           self.process_transaction(verbose, &transaction).unwrap();
-            if verbose {
-                let txid = match transaction.tx {
-                    Some(v) => v,
-                    None => 0
-                };
-                eprintln!("processed dispute txid:[{:?}] transaction:[{:?}]",txid,transaction);
-            }
-        }
-        for transaction in resolves {
-           self.process_transaction(verbose, &transaction).unwrap();
-           if verbose {
-                let txid = match transaction.tx {
-                    Some(v) => v,
-                    None => 0
-                };
-                eprintln!("processed resolve txid:[{:?}] transaction:[{:?}]",txid,transaction);
-            }
-        }
-        for transaction in chargebacks {
-           self.process_transaction(verbose, &transaction).unwrap();
-           if verbose {
-                let txid = match transaction.tx {
-                    Some(v) => v,
-                    None => 0
-                };
-                eprintln!("processed chargeback txid:[{:?}] transaction:[{:?}]",txid,transaction);
-            }
-        }
-    
+    fn accounts(&self) -> Box<dyn Iterator<Item = &AccountStatus> + '_> {
+        Box::new(self.by_account.values())
+    }
+
+    fn account_of(&self, trans: &Transaction) -> Option<&AccountStatus> {
+        self.account_key(trans).and_then(|key| self.by_account.get(&key))
+    }
+
+    fn counterparty_of(&self, trans: &Transaction) -> Option<&AccountStatus> {
+        self.counterparty_key(trans).and_then(|key| self.by_account.get(&key))
     }
 
-    fn run_report(&self) {
-        let all_clients = &self.by_client_id;
-        println!("\nclient, available, held, total, locked");
-        for (cid, cas) in all_clients {
-            let total = cas.available + cas.held;
-            println!("{},{},{},{},{}",cid,cas.available,cas.held,total,cas.locked);
+    fn trial_balance(&self) -> TrialBalance {
+        self.book.trial_balance()
+    }
+
+    fn save_snapshot(&self, out: &mut dyn std::io::Write) -> Result<(), LedgerError> {
+        snapshot::write_snapshot(&mut self.by_account.values(), &mut self.by_transaction_id.values(), out)
+    }
+
+    fn load_snapshot(&mut self, rdr: &mut dyn std::io::Read) -> Result<(), LedgerError> {
+        let restored = snapshot::read_snapshot(rdr)?;
+        self.by_account = restored.by_account;
+        self.by_transaction_id = restored.by_transaction_id;
+        self.book = restored.book;
+        self.index_authorizations();
+        if let Some(policy) = self.audit.as_ref().map(|a| a.policy()) {
+            self.set_audit(policy);
         }
+        Ok(())
     }
-    
-}
\ No newline at end of file
+}
@@ -1,14 +1,29 @@
 use rust_decimal::Decimal;
-use std::collections::BTreeMap;
-use serde::Deserialize;
-use std::error::Error;
+use serde::{Deserialize, Serialize};
+use error::{BankexError, LedgerError, ReadError};
+use postings::{LedgerAccount, TrialBalance};
+use rejected::{IgnoreRejections, Rejection, RejectionSink};
 pub mod args;
+pub mod audit;
+pub mod disk;
+pub mod error;
+pub mod events;
+pub mod journal;
+pub mod policy;
+pub mod precision;
+pub mod postings;
 pub mod read;
+pub mod rejected;
+pub mod report;
+pub mod server;
+pub mod sharded;
+pub mod snapshot;
+pub mod statement;
 pub mod ledger;
 
 const MAX_DECIMAL_PLACES: u32 = 4;
 
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 enum TransactionType {
    #[serde(rename = "deposit")]
    Deposit,
@@ -19,32 +34,298 @@ enum TransactionType {
    #[serde(rename = "resolve")]
    Resolve,
    #[serde(rename = "chargeback")]
-   Chargeback
+   Chargeback,
+   #[serde(rename = "transfer")]
+   Transfer,
+   #[serde(rename = "authorize")]
+   Authorize,
+   #[serde(rename = "capture")]
+   Capture,
+   #[serde(rename = "void")]
+   Void,
+   #[serde(rename = "unlock")]
+   Unlock,
+   #[serde(rename = "adjust_credit")]
+   AdjustCredit,
+   #[serde(rename = "adjust_debit")]
+   AdjustDebit
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl TransactionType {
+   pub fn as_str(&self) -> &'static str {
+      match self {
+         TransactionType::Deposit => "deposit",
+         TransactionType::Withdrawal => "withdrawal",
+         TransactionType::Dispute => "dispute",
+         TransactionType::Resolve => "resolve",
+         TransactionType::Chargeback => "chargeback",
+         TransactionType::Transfer => "transfer",
+         TransactionType::Authorize => "authorize",
+         TransactionType::Capture => "capture",
+         TransactionType::Void => "void",
+         TransactionType::Unlock => "unlock",
+         TransactionType::AdjustCredit => "adjust_credit",
+         TransactionType::AdjustDebit => "adjust_debit",
+      }
+   }
+
+   // operator transactions, only accepted through Ledger::process_admin and never from partner input
+   pub fn is_admin(&self) -> bool {
+      matches!(self, TransactionType::Unlock | TransactionType::AdjustCredit | TransactionType::AdjustDebit)
+   }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Transaction {
    #[serde(rename = "type")]
    transaction_type: TransactionType,
    client: Option<u16>,
    pub tx: Option<u32>,
-   amount: Option<Decimal>
+   amount: Option<Decimal>,
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   destination: Option<u16>, // client a transfer moves the amount to, client is where it comes from
+   #[serde(default, alias = "currency", skip_serializing_if = "Option::is_none")]
+   asset: Option<String>, // none for the default asset of single asset input
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   reason: Option<String>, // why an operator made an admin transaction
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   timestamp: Option<u64>, // seconds since the unix epoch, only used to expire authorizations
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   expires_at: Option<u64>, // timestamp from which an authorization is released if it was not captured
+   #[serde(skip)]
+   pub rounded_from: Option<Decimal>, // amount as read when the reader had to round it
+   #[serde(skip)]
+   pub line: Option<u64> // input line the transaction was read from, set by the reader
 }
-#[derive(Debug, Clone)]
+// a client holds one account per asset, each with its own balances and lock
+pub type AccountKey = (u16, Option<String>);
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AccountStatus {
-   client:   u16,
+   pub client:   u16,
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   pub asset: Option<String>,
    pub available:    Decimal,
    pub held:    Decimal,
-   total:    Decimal,
-   pub locked:  bool
+   #[serde(default)]
+   pub authorized: Decimal, // reserved by open authorizations, apart from the held funds of disputes
+   pub total:    Decimal,
+   pub locked:  bool,
+   #[serde(default)]
+   pub applied: u64 // transactions accepted on the account, authorizations expire by it
+}
+
+impl AccountStatus {
+   pub fn key(&self) -> AccountKey {
+      (self.client, self.asset.clone())
+   }
+}
+
+// lifecycle of a stored deposit or withdrawal with respect to disputes
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum DisputeState {
+   Processed,
+   Disputed,
+   Resolved,
+   ChargedBack
+}
+
+impl DisputeState {
+   // only Processed -> Disputed -> Resolved | ChargedBack is legal, resolved and charged back are final
+   pub fn transition(self, tx: u32, to: DisputeState) -> Result<DisputeState, LedgerError> {
+      match (self, to) {
+         (DisputeState::Processed, DisputeState::Disputed)
+         | (DisputeState::Disputed, DisputeState::Resolved)
+         | (DisputeState::Disputed, DisputeState::ChargedBack) => Ok(to),
+         _ => Err(LedgerError::IllegalTransition { tx, from: self, to })
+      }
+   }
+}
+
+// lifecycle of a stored authorization, a capture or void closes it and so does expiry
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum AuthorizationState {
+   Open,
+   Captured,
+   Voided,
+   Expired
+}
+
+// the hold an authorize row placed, released once the account's applied count reaches expires_after
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Authorization {
+   pub state: AuthorizationState,
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   pub captured: Option<Decimal>,
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   pub expires_after: Option<u64>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransactionRecord {
+   pub transaction: Transaction,
+   pub state: DisputeState,
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   pub authorization: Option<Authorization>
+}
+
+impl TransactionRecord {
+   pub fn new(transaction: Transaction) -> Self {
+      Self { transaction, state: DisputeState::Processed, authorization: None }
+   }
+}
+
+// counts for one call to read_transactions, processed is every row read whether it was accepted or rejected
+#[derive(Debug, Serialize, Default, PartialEq, Clone, Copy)]
+pub struct RunSummary {
+   pub processed: u64,
+   pub accepted: u64,
+   pub rejected: u64
+}
+
+// a point in the input, either the line a row was read from or the number of transactions accepted so far
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AsOf {
+   Line(u64),
+   Seq(u64)
 }
 
 pub trait Ledger {
-   fn process_transaction(&mut self,verbose: bool, trans: &Transaction) -> Result<(), Box<dyn Error>>;
-   fn get_funds_available(&self, client_id: u16) -> Result<Decimal, Box<dyn Error>>;
-   fn get_funds_held(&self, client_id: u16) -> Result<Decimal, Box<dyn Error>>;
-   fn get_funds_total(&self, client_id: u16) -> Result<Decimal, Box<dyn Error>>;
-   fn verify_transaction(&self, trans: &Transaction) -> Result<(), Box<dyn Error>>;
-   fn read_transactions(&mut self, verbose: bool, withdraw_deposits: BTreeMap<u32, Transaction>, disputes: Vec<Transaction>, resolves: Vec<Transaction>, chargebacks: Vec<Transaction>);
-   fn run_report(&self);
-}
\ No newline at end of file
+   fn process_transaction(&mut self,verbose: bool, trans: &Transaction) -> Result<(), LedgerError>;
+   // balances of the client's account in the default asset
+   fn get_funds_available(&self, client_id: u16) -> Result<Decimal, LedgerError>;
+   fn get_funds_held(&self, client_id: u16) -> Result<Decimal, LedgerError>;
+   fn get_funds_total(&self, client_id: u16) -> Result<Decimal, LedgerError>;
+   fn verify_transaction(&self, trans: &Transaction) -> Result<(), LedgerError>;
+
+   // transactions are applied as they are pulled from the iterator so file order is preserved and
+   // memory use is bounded by the number of accounts and stored transactions, not by the input size.
+   // rows that fail to parse or are refused by the ledger go to the rejection sink and processing carries on,
+   // only failing to read the input or write the rejections stops the run
+   fn read_transactions(&mut self, verbose: bool, transactions: &mut dyn Iterator<Item = read::TransactionResult>, rejections: &mut dyn RejectionSink) -> Result<RunSummary, BankexError> {
+      self.read_transactions_until(verbose, transactions, rejections, None)
+   }
+
+   // same as read_transactions but stops before the first row past as_of, leaving the rest of the input unread
+   fn read_transactions_until(&mut self, verbose: bool, transactions: &mut dyn Iterator<Item = read::TransactionResult>, rejections: &mut dyn RejectionSink, as_of: Option<AsOf>) -> Result<RunSummary, BankexError> {
+      apply_rows(verbose, transactions, rejections, as_of, &mut |trans| self.process_transaction(verbose, trans))
+   }
+
+   // unlock, adjust_credit and adjust_debit, each with the operator's reason. these are refused by process_transaction
+   // so partner input can never carry them, and admin input can carry nothing else
+   fn process_admin(&mut self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError>;
+   fn read_admin_transactions(&mut self, verbose: bool, transactions: &mut dyn Iterator<Item = read::TransactionResult>, rejections: &mut dyn RejectionSink) -> Result<RunSummary, BankexError> {
+      apply_rows(verbose, transactions, rejections, None, &mut |trans| self.process_admin(verbose, trans))
+   }
+
+   // the client's account as it stood once the input up to as_of was applied on top of the current state,
+   // the ledger is left at that point. rejected rows are skipped as in a normal run
+   fn account_as_of(&mut self, verbose: bool, client: u16, as_of: AsOf, transactions: &mut dyn Iterator<Item = read::TransactionResult>) -> Result<AccountStatus, BankexError> {
+      self.read_transactions_until(verbose, transactions, &mut IgnoreRejections, Some(as_of))?;
+      match self.accounts().find(|cas| cas.client == client) {
+         Some(cas) => Ok(cas.clone()),
+         None => Err(LedgerError::UnknownAccount { client }.into()),
+      }
+   }
+
+   // every account ordered by client id and asset, the default asset first
+   fn accounts(&self) -> Box<dyn Iterator<Item = &AccountStatus> + '_>;
+   // the account trans was applied to, disputes belong to the account of the transaction they refer to
+   fn account_of(&self, trans: &Transaction) -> Option<&AccountStatus> {
+      self.accounts().find(|cas| Some(cas.client) == trans.client && cas.asset == trans.asset)
+   }
+   // the second account trans changed, the destination of a transfer or the source a transfer is charged back to
+   fn counterparty_of(&self, _trans: &Transaction) -> Option<&AccountStatus> {
+      None
+   }
+   fn run_report(&self, out: &mut dyn std::io::Write) -> Result<(), BankexError> {
+      report::write_accounts(out, &mut self.accounts(), &precision::PrecisionTable::default())?;
+      Ok(())
+   }
+   // balances of the double entry books behind the client accounts
+   fn trial_balance(&self) -> TrialBalance;
+   // the books of every asset must add up to zero and every client sub-account must agree with the account it backs
+   fn check_trial_balance(&self) -> Result<TrialBalance, LedgerError> {
+      let trial_balance = self.trial_balance();
+      for (asset, total) in trial_balance.totals() {
+         if !total.is_zero() {
+            return Err(LedgerError::Unbalanced { account: postings::book_name(asset.as_deref(), "total"), expected: Decimal::new(0,0), found: total });
+         }
+      }
+      for cas in self.accounts() {
+         let sub_accounts = [
+            (LedgerAccount::ClientAvailable(cas.client), cas.available),
+            (LedgerAccount::ClientHeld(cas.client), cas.held),
+            (LedgerAccount::ClientAuthorized(cas.client), cas.authorized),
+         ];
+         for (account, expected) in sub_accounts {
+            let found = trial_balance.balance_in(cas.asset.as_deref(), account);
+            if found != expected {
+               return Err(LedgerError::Unbalanced { account: postings::book_name(cas.asset.as_deref(), &account.to_string()), expected, found });
+            }
+         }
+      }
+      Ok(trial_balance)
+   }
+   // full state including dispute status, written as a versioned snapshot
+   fn save_snapshot(&self, out: &mut dyn std::io::Write) -> Result<(), LedgerError>;
+   // replaces the whole state with the one held in the snapshot
+   fn load_snapshot(&mut self, rdr: &mut dyn std::io::Read) -> Result<(), LedgerError>;
+}
+
+// pulls rows until the input ends or as_of is passed and hands each transaction to apply, see Ledger::read_transactions
+fn apply_rows(verbose: bool, transactions: &mut dyn Iterator<Item = read::TransactionResult>, rejections: &mut dyn RejectionSink, as_of: Option<AsOf>, apply: &mut dyn FnMut(&Transaction) -> Result<(), LedgerError>) -> Result<RunSummary, BankexError> {
+   let mut summary = RunSummary::default();
+   loop {
+      if as_of == Some(AsOf::Seq(summary.accepted)) {
+         break;
+      }
+      let record = match transactions.next() {
+         Some(v) => v,
+         None => break,
+      };
+      if let Some(AsOf::Line(last)) = as_of {
+         let line = match &record {
+            Ok(t) => t.line,
+            Err(e) => e.line(),
+         };
+         if line.is_some_and(|line| line > last) {
+            break;
+         }
+      }
+      summary.processed += 1;
+      let transaction = match record {
+         Ok(t) => t,
+         Err(ReadError::Io(e)) => return Err(ReadError::Io(e).into()),
+         Err(e) => {
+            if verbose {
+               eprintln!("skipping row reason:[{}]",e);
+            }
+            summary.rejected += 1;
+            rejections.reject(Rejection::from_read_error(&e))?;
+            continue;
+         }
+      };
+      if let Err(e) = apply(&transaction) {
+         if e.aborts_run() {
+            return Err(e.into());
+         }
+         if verbose {
+            eprintln!("skipping {:?} transaction reason:[{}]",transaction.transaction_type,e);
+         }
+         summary.rejected += 1;
+         rejections.reject(Rejection::from_transaction(&transaction, &e))?;
+         continue;
+      }
+      summary.accepted += 1;
+      // the difference rounding made to an accepted amount goes to the exceptions so it can be accounted for
+      if let Some(original) = transaction.rounded_from {
+         rejections.reject(Rejection::from_rounding(&transaction, original))?;
+      }
+      if verbose {
+         let txid = transaction.tx.unwrap_or_default();
+         eprintln!("processed {:?} txid:[{:?}] transaction:[{:?}]",transaction.transaction_type,txid,transaction);
+      }
+   }
+   Ok(summary)
+}
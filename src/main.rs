@@ -1,22 +1,209 @@
-use bankex::{Ledger, args::Args, ledger::InMemoryLedger, read};
+use bankex::{Ledger, args::{Args, Command}, disk::DiskLedger, events::EventSourcedLedger, ledger::InMemoryLedger, read, report, server::Server, sharded::ShardedLedger, statement};
+use bankex::journal::{self, JournalSink, JournalWriter};
+use bankex::rejected::{CsvRejectionWriter, IgnoreRejections, JsonRejectionWriter, RejectionSink};
+use std::{fs::File, io::{self, BufReader, BufWriter, Write}, path::Path};
 
 fn main() {
     let args = Args::parse();
-    let Args {
-        infile,
-        verbose,
-    } = args;
-
-    if verbose == true {
-        eprintln!("argument parse result infile:[{}] verbose:[{}]",infile,verbose);
+    if args.verbose {
+        eprintln!("argument parse result infile:[{}] verbose:[{}] ledger dir:[{:?}] workers:[{}] load snapshot:[{:?}] save snapshot:[{:?}] rejected:[{:?}]"
+            ,args.infile,args.verbose,args.ledger_dir,args.workers,args.load_snapshot,args.save_snapshot,args.rejected);
+    }
+    if let Command::Serve { listen, shards } = &args.command {
+        let server = Server::bind(listen.as_str(), *shards, args.precision.clone(), args.verbose).unwrap();
+        server.ledger().set_locked_policy(args.locked_policy.clone());
+        if let Some(n) = args.authorization_expiry {
+            server.ledger().set_authorization_expiry(n);
+        }
+        if args.verbose {
+            eprintln!("listening on:[{:?}] shards:[{}]",server.local_addr(),shards);
+        }
+        server.run().unwrap();
+        return;
+    }
+    if let Command::VerifyJournal { file } = &args.command {
+        let mut rdr = BufReader::new(File::open(file).unwrap());
+        match journal::verify_journal(&mut rdr) {
+            Ok(count) => println!("journal ok, {} entries verified", count),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Command::Balance { client, as_of } = &args.command {
+        let mut ledger = InMemoryLedger::default();
+        ledger.set_locked_policy(args.locked_policy.clone());
+        if let Some(n) = args.authorization_expiry {
+            ledger.set_authorization_expiry(n);
+        }
+        let mut transactions = read::transaction_reader(args.verbose, &args.infile, args.input_format, &args.precision).unwrap();
+        match ledger.account_as_of(args.verbose, *client, *as_of, &mut transactions) {
+            Ok(cas) => report::write_accounts(&mut io::stdout(), &mut std::iter::once(&cas), &args.precision).unwrap(),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Command::Statement { client, format } = &args.command {
+        let mut ledger = InMemoryLedger::default();
+        ledger.set_locked_policy(args.locked_policy.clone());
+        if let Some(n) = args.authorization_expiry {
+            ledger.set_authorization_expiry(n);
+        }
+        let mut transactions = read::transaction_reader(args.verbose, &args.infile, args.input_format, &args.precision).unwrap();
+        statement::write_statement(args.verbose, *client, &mut ledger, &mut transactions, *format, &args.precision, &mut io::stdout()).unwrap();
+        return;
     }
-    let (withdraw_deposits, disputes, resolves, chargebacks) = read::transaction_reader(verbose, &infile).unwrap();
-    if verbose {
-        eprintln!("got [{:?}] withdraw or deposit transactions",withdraw_deposits.len());
+    match (&args.ledger_dir, &args.event_log) {
+        (_, Some(path)) => {
+            let mut ledger = EventSourcedLedger::open(Path::new(path)).unwrap();
+            if let Some(journal) = open_journal(&args) {
+                ledger.set_journal(journal);
+            }
+            if let Some(policy) = args.audit {
+                ledger.set_audit(policy);
+            }
+            ledger.set_locked_policy(args.locked_policy.clone());
+            if let Some(n) = args.authorization_expiry {
+                ledger.set_authorization_expiry(n);
+            }
+            run(&mut ledger, &args);
+            finish_journal(ledger.take_journal());
+            ledger.log().flush().unwrap();
+        },
+        (Some(dir), None) => {
+            let mut ledger = DiskLedger::open(Path::new(dir)).unwrap();
+            if let Some(journal) = open_journal(&args) {
+                ledger.set_journal(journal);
+            }
+            if let Some(policy) = args.audit {
+                ledger.set_audit(policy);
+            }
+            ledger.set_locked_policy(args.locked_policy.clone());
+            if let Some(n) = args.authorization_expiry {
+                ledger.set_authorization_expiry(n);
+            }
+            run(&mut ledger, &args);
+            finish_journal(ledger.take_journal());
+            ledger.compact().unwrap();
+        },
+        (None, None) if args.workers > 1 => {
+            let mut ledger = ShardedLedger::new(args.workers);
+            ledger.set_locked_policy(args.locked_policy.clone());
+            if let Some(n) = args.authorization_expiry {
+                ledger.set_authorization_expiry(n);
+            }
+            run(&mut ledger, &args);
+        },
+        (None, None) => {
+            let mut ledger = InMemoryLedger::default();
+            if let Some(journal) = open_journal(&args) {
+                ledger.set_journal(journal);
+            }
+            if let Some(policy) = args.audit {
+                ledger.set_audit(policy);
+            }
+            ledger.set_locked_policy(args.locked_policy.clone());
+            if let Some(n) = args.authorization_expiry {
+                ledger.set_authorization_expiry(n);
+            }
+            run(&mut ledger, &args);
+            finish_journal(ledger.take_journal());
+        }
     }
-    let mut ledger = InMemoryLedger::default();
+}
 
-    ledger.read_transactions(verbose, withdraw_deposits, disputes, resolves, chargebacks);
+fn open_journal(args: &Args) -> Option<Box<dyn JournalSink>> {
+    let path = args.journal.as_ref()?;
+    let out = Box::new(BufWriter::new(File::create(path).unwrap()));
+    Some(Box::new(JournalWriter::new(out)))
+}
 
-    ledger.run_report();
+fn finish_journal(journal: Option<Box<dyn JournalSink>>) {
+    if let Some(mut journal) = journal {
+        journal.finish().unwrap();
+    }
+}
+
+// the csv exceptions file has an asset or destination column when the input or the admin file has one
+fn input_columns(args: &Args) -> Vec<String> {
+    let mut columns = read::input_columns(&args.infile, args.input_format).unwrap();
+    if let Some(path) = &args.admin {
+        columns.extend(read::input_columns(path, None).unwrap());
+    }
+    columns
+}
+
+// same steps for every backend: restore, apply the input, checkpoint, report
+fn run(ledger: &mut dyn Ledger, args: &Args) {
+    let verbose = args.verbose;
+    let mut rejections: Box<dyn RejectionSink> = match &args.rejected {
+        Some(path) => {
+            let out = Box::new(BufWriter::new(File::create(path).unwrap()));
+            match args.rejected_format.as_str() {
+                "json" => Box::new(JsonRejectionWriter::new(out)),
+                _ => {
+                    let columns = input_columns(args);
+                    let has = |name: &str| columns.iter().any(|column| column == name);
+                    Box::new(CsvRejectionWriter::new(out)
+                        .with_asset_column(has("asset") || has("currency"))
+                        .with_destination_column(has("destination")))
+                },
+            }
+        },
+        None => Box::new(IgnoreRejections),
+    };
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path).unwrap())),
+        None => Box::new(io::stdout()),
+    };
+    if let Some(path) = &args.load_snapshot {
+        let mut rdr = BufReader::new(File::open(path).unwrap());
+        ledger.load_snapshot(&mut rdr).unwrap();
+    }
+    let mut transactions = read::transaction_reader(verbose, &args.infile, args.input_format, &args.precision).unwrap();
+    let mut summary = match ledger.read_transactions(verbose, &mut transactions, rejections.as_mut()) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    // operator corrections go on top of the partner input, their rejections end up with the others
+    if let Some(path) = &args.admin {
+        let mut admin_transactions = read::transaction_reader(verbose, path, None, &args.precision).unwrap();
+        match ledger.read_admin_transactions(verbose, &mut admin_transactions, rejections.as_mut()) {
+            Ok(v) => {
+                summary.processed += v.processed;
+                summary.accepted += v.accepted;
+                summary.rejected += v.rejected;
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+    rejections.finish().unwrap();
+    if let Some(path) = &args.save_snapshot {
+        let mut snapshot_out = BufWriter::new(File::create(path).unwrap());
+        ledger.save_snapshot(&mut snapshot_out).unwrap();
+    }
+    // a run whose books do not add up must not produce a report that looks fine
+    match ledger.check_trial_balance() {
+        Ok(trial_balance) => {
+            if let Some(path) = &args.trial_balance {
+                trial_balance.write_csv(&mut BufWriter::new(File::create(path).unwrap()), &args.precision).unwrap();
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+    report::write_report(out.as_mut(), args.output_format, &mut ledger.accounts(), &summary, &args.precision).unwrap();
 }
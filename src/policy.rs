@@ -0,0 +1,43 @@
+use crate::{Transaction, TransactionType};
+
+const TRANSACTION_TYPES: [TransactionType; 9] = [
+    TransactionType::Deposit,
+    TransactionType::Withdrawal,
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+    TransactionType::Transfer,
+    TransactionType::Authorize,
+    TransactionType::Capture,
+    TransactionType::Void,
+];
+
+// which transaction types a locked account still accepts, by default a locked account accepts nothing
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct LockedAccountPolicy {
+    allowed: Vec<TransactionType>,
+}
+
+impl LockedAccountPolicy {
+    // names are the transaction types as they appear in the input, e.g. "dispute,resolve"
+    pub fn allowing(names: &str) -> Result<Self, String> {
+        let mut allowed = Vec::new();
+        for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match TRANSACTION_TYPES.iter().find(|t| t.as_str() == name) {
+                Some(t) if !allowed.contains(t) => allowed.push(t.clone()),
+                Some(_) => {},
+                None => return Err(format!("unknown transaction type:[{}]", name)),
+            }
+        }
+        Ok(Self { allowed })
+    }
+
+    // every type, for transactions that were already let through once
+    pub fn allowing_all() -> Self {
+        Self { allowed: TRANSACTION_TYPES.to_vec() }
+    }
+
+    pub fn allows(&self, trans: &Transaction) -> bool {
+        self.allowed.contains(&trans.transaction_type)
+    }
+}
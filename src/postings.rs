@@ -0,0 +1,162 @@
+use crate::{AccountStatus, DisputeState, TransactionRecord, TransactionType};
+use crate::precision::PrecisionTable;
+use crate::report::format_amount_to;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// accounts money can sit in. every client has an available and a held sub-account, and an authorized one
+// for funds reserved by authorizations. funds come in from
+// and go back out to the partner through the settlement account, funds lost to a chargeback end up in chargeback losses
+// and operator adjustments are booked against operator adjustments
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub enum LedgerAccount {
+    PartnerSettlement,
+    ChargebackLosses,
+    OperatorAdjustments,
+    ClientAvailable(u16),
+    ClientHeld(u16),
+    ClientAuthorized(u16),
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerAccount::PartnerSettlement => write!(f, "partner_settlement"),
+            LedgerAccount::ChargebackLosses => write!(f, "chargeback_losses"),
+            LedgerAccount::OperatorAdjustments => write!(f, "operator_adjustments"),
+            LedgerAccount::ClientAvailable(cid) => write!(f, "client:{}:available", cid),
+            LedgerAccount::ClientHeld(cid) => write!(f, "client:{}:held", cid),
+            LedgerAccount::ClientAuthorized(cid) => write!(f, "client:{}:authorized", cid),
+        }
+    }
+}
+
+// name of an account or total in the books of asset, the default asset keeps the plain name
+pub fn book_name(asset: Option<&str>, name: &str) -> String {
+    match asset {
+        Some(asset) => format!("{}:{}", asset, name),
+        None => name.to_string(),
+    }
+}
+
+// moves amount out of debit into credit, a posting always has both sides so money can not appear or vanish.
+// both sides are in the books of the same asset, none being the default asset
+#[derive(Debug, PartialEq, Clone)]
+pub struct Posting {
+    pub tx: u32,
+    pub asset: Option<String>,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: Decimal,
+}
+
+// running balance of every account, a balance goes down when the account is debited and up when it is credited.
+// every asset has its own books and the balances of all accounts of an asset always add up to zero
+#[derive(Debug, Default, Clone)]
+pub struct PostingBook {
+    balances: BTreeMap<(Option<String>, LedgerAccount), Decimal>,
+}
+
+impl PostingBook {
+    pub fn post(&mut self, posting: &Posting) {
+        *self.balances.entry((posting.asset.clone(), posting.debit)).or_default() -= posting.amount;
+        *self.balances.entry((posting.asset.clone(), posting.credit)).or_default() += posting.amount;
+    }
+
+    // books for state that was restored without its history, client sub-accounts start from the account
+    // balances, charged back transactions are losses, adjustments come from the stored operator transactions
+    // and whatever is left was settled with the partner
+    pub fn from_state<'a>(accounts: impl Iterator<Item = &'a AccountStatus>, records: impl Iterator<Item = &'a TransactionRecord>) -> Self {
+        let mut book = Self::default();
+        for cas in accounts {
+            let asset = cas.asset.clone();
+            book.post(&Posting { tx: 0, asset: asset.clone(), debit: LedgerAccount::PartnerSettlement, credit: LedgerAccount::ClientAvailable(cas.client), amount: cas.available });
+            book.post(&Posting { tx: 0, asset: asset.clone(), debit: LedgerAccount::PartnerSettlement, credit: LedgerAccount::ClientHeld(cas.client), amount: cas.held });
+            if !cas.authorized.is_zero() {
+                book.post(&Posting { tx: 0, asset, debit: LedgerAccount::PartnerSettlement, credit: LedgerAccount::ClientAuthorized(cas.client), amount: cas.authorized });
+            }
+        }
+        for record in records {
+            let tid = record.transaction.tx.unwrap_or_default();
+            let amount = record.transaction.amount.unwrap_or_default();
+            let (debit, credit) = match record.transaction.transaction_type {
+                // transfers and their chargebacks only ever move funds between client accounts
+                TransactionType::Transfer => continue,
+                _ if record.state == DisputeState::ChargedBack => (LedgerAccount::PartnerSettlement, LedgerAccount::ChargebackLosses),
+                TransactionType::AdjustCredit => (LedgerAccount::OperatorAdjustments, LedgerAccount::PartnerSettlement),
+                TransactionType::AdjustDebit => (LedgerAccount::PartnerSettlement, LedgerAccount::OperatorAdjustments),
+                _ => continue,
+            };
+            book.post(&Posting { tx: tid, asset: record.transaction.asset.clone(), debit, credit, amount });
+        }
+        book
+    }
+
+    pub fn trial_balance(&self) -> TrialBalance {
+        TrialBalance { balances: self.balances.clone() }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TrialBalanceRow {
+    account: String,
+    balance: String,
+}
+
+// balances of every account of every asset at one point, used to prove the books add up after a run
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TrialBalance {
+    pub balances: BTreeMap<(Option<String>, LedgerAccount), Decimal>,
+}
+
+impl TrialBalance {
+    // zero when every movement had a counterparty
+    pub fn total(&self) -> Decimal {
+        self.balances.values().copied().sum()
+    }
+
+    // the total of the books of each asset, each one zero when every movement had a counterparty
+    pub fn totals(&self) -> BTreeMap<Option<String>, Decimal> {
+        let mut totals: BTreeMap<Option<String>, Decimal> = BTreeMap::new();
+        for ((asset, _), balance) in &self.balances {
+            *totals.entry(asset.clone()).or_default() += *balance;
+        }
+        totals
+    }
+
+    // balance in the books of the default asset
+    pub fn balance(&self, account: LedgerAccount) -> Decimal {
+        self.balance_in(None, account)
+    }
+
+    pub fn balance_in(&self, asset: Option<&str>, account: LedgerAccount) -> Decimal {
+        self.balances.get(&(asset.map(String::from), account)).copied().unwrap_or_default()
+    }
+
+    // used by ledgers that keep their books in several parts
+    pub fn merge(&mut self, other: TrialBalance) {
+        for (account, balance) in other.balances {
+            *self.balances.entry(account).or_default() += balance;
+        }
+    }
+
+    // the accounts of each asset are followed by that asset's total, accounts of other assets than the default
+    // are prefixed with the asset. balances have the decimal places of their asset
+    pub fn write_csv(&self, out: &mut dyn std::io::Write, precision: &PrecisionTable) -> std::io::Result<()> {
+        let mut wtr = csv::Writer::from_writer(out);
+        let totals = self.totals();
+        for (asset, total) in &totals {
+            let places = precision.places(asset.as_deref());
+            for ((_, account), balance) in self.balances.iter().filter(|((a, _), _)| a == asset) {
+                wtr.serialize(TrialBalanceRow { account: book_name(asset.as_deref(), &account.to_string()), balance: format_amount_to(*balance, places) })?;
+            }
+            wtr.serialize(TrialBalanceRow { account: book_name(asset.as_deref(), "total"), balance: format_amount_to(*total, places) })?;
+        }
+        if totals.is_empty() {
+            wtr.serialize(TrialBalanceRow { account: "total".to_string(), balance: format_amount_to(Decimal::new(0, 0), precision.places(None)) })?;
+        }
+        wtr.flush()
+    }
+}
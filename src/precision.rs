@@ -0,0 +1,87 @@
+use crate::MAX_DECIMAL_PLACES;
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::collections::BTreeMap;
+
+// most places a rust_decimal can hold
+const MAX_SCALE: u32 = 28;
+
+// what happens to an amount with more places than its asset allows. half-up and half-down send a midpoint
+// away from and towards zero, half-even to the nearest even digit
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum Rounding {
+    Reject,
+    Truncate,
+    HalfUp,
+    #[default]
+    HalfEven,
+    HalfDown,
+}
+
+impl Rounding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "reject" => Some(Rounding::Reject),
+            "truncate" => Some(Rounding::Truncate),
+            "half-up" => Some(Rounding::HalfUp),
+            "half-even" => Some(Rounding::HalfEven),
+            "half-down" => Some(Rounding::HalfDown),
+            _ => None,
+        }
+    }
+
+    // None when over-precise amounts are rejected instead
+    pub fn round(self, amount: Decimal, places: u32) -> Option<Decimal> {
+        let strategy = match self {
+            Rounding::Reject => return None,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfDown => RoundingStrategy::MidpointTowardZero,
+        };
+        Some(amount.round_dp_with_strategy(places, strategy))
+    }
+}
+
+// decimal places of each asset, assets not in the table and the default asset have MAX_DECIMAL_PLACES.
+// amounts with more places are rounded when read, or rejected, and report balances are written with
+// exactly the places of their asset
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct PrecisionTable {
+    places: BTreeMap<String, u32>,
+    rounding: Rounding,
+}
+
+impl PrecisionTable {
+    // spec lists asset=places pairs as in the input, e.g. "BTC=8,ETH=18,JPY=0"
+    pub fn from_spec(spec: &str) -> Result<Self, String> {
+        let mut places = BTreeMap::new();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (asset, n) = match pair.split_once('=') {
+                Some((asset, n)) => (asset.trim(), n.trim()),
+                None => return Err(format!("expected asset=places, found:[{}]", pair)),
+            };
+            let n = match n.parse::<u32>() {
+                Ok(n) if n <= MAX_SCALE => n,
+                _ => return Err(format!("places of asset:[{}] must be a number from 0 to {}, found:[{}]", asset, MAX_SCALE, n)),
+            };
+            if asset.is_empty() {
+                return Err(format!("no asset in:[{}]", pair));
+            }
+            places.insert(asset.to_string(), n);
+        }
+        Ok(Self { places, rounding: Rounding::default() })
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    pub fn rounding(&self) -> Rounding {
+        self.rounding
+    }
+
+    pub fn places(&self, asset: Option<&str>) -> u32 {
+        asset.and_then(|asset| self.places.get(asset)).copied().unwrap_or(MAX_DECIMAL_PLACES)
+    }
+}
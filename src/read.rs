@@ -1,75 +1,244 @@
 use csv::Trim;
 //use serde::Deserialize;
-use crate::{MAX_DECIMAL_PLACES, Transaction};
-use csv::{ReaderBuilder};
-use std::{error::Error, fs::File, io::BufReader};
-use rust_decimal::Decimal;
-use std::collections::BTreeMap; // use this since it keeps the internal ordering sorted by key
+use crate::{Transaction, error::ReadError, precision::PrecisionTable};
+use csv::{Reader, ReaderBuilder, StringRecord};
+use std::{fs::File, io::{BufRead, BufReader, Lines}, path::Path};
 
-pub fn transaction_reader(verbose: bool, path: &str) -> Result<(BTreeMap<u32, Transaction>, Vec<Transaction>, Vec<Transaction>, Vec<Transaction>), Box<dyn Error>> {
+pub type TransactionResult = Result<Transaction, ReadError>;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum InputFormat {
+    Csv,
+    Jsonl,
+}
+
+impl InputFormat {
+    // .jsonl and .ndjson files are json lines, anything else is read as csv
+    pub fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()) {
+            Some(ext) if ext == "jsonl" || ext == "ndjson" => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(InputFormat::Csv),
+            "jsonl" => Some(InputFormat::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+// pulls one csv record at a time so input is never buffered as a whole, records come out in file order
+pub struct TransactionReader {
+    verbose: bool,
+    precision: PrecisionTable,
+    rdr: Reader<Box<dyn std::io::Read>>,
+    headers: StringRecord,
+    record: StringRecord,
+}
+
+// format None picks the format from the file extension
+pub fn transaction_reader(verbose: bool, path: &str, format: Option<InputFormat>, precision: &PrecisionTable) -> Result<Box<dyn Iterator<Item = TransactionResult>>, ReadError> {
     let f = File::open(path)?;
     let br = BufReader::new(f);
-    return transaction_reader_from(verbose, Box::new(br));
+    match format.unwrap_or_else(|| InputFormat::from_path(path)) {
+        InputFormat::Csv => Ok(Box::new(transaction_reader_from(verbose, Box::new(br))?.with_precision(precision.clone()))),
+        InputFormat::Jsonl => Ok(Box::new(jsonl_reader_from(verbose, Box::new(br)).with_precision(precision.clone()))),
+    }
+}
+
+// names of the columns of the input, from the csv header or the keys of every json line, so a report can
+// decide on optional columns before the first row is read
+pub fn input_columns(path: &str, format: Option<InputFormat>) -> Result<Vec<String>, ReadError> {
+    let br = BufReader::new(File::open(path)?);
+    match format.unwrap_or_else(|| InputFormat::from_path(path)) {
+        InputFormat::Csv => {
+            let mut rdr = ReaderBuilder::new().flexible(true).trim(Trim::All).from_reader(br);
+            Ok(rdr.headers()?.iter().map(String::from).collect())
+        },
+        // json lines need not all have the same keys, so the whole file is read for them
+        InputFormat::Jsonl => {
+            let mut columns: Vec<String> = Vec::new();
+            for text in br.lines() {
+                if let Ok(serde_json::Value::Object(fields)) = serde_json::from_str::<serde_json::Value>(&text?) {
+                    for (key, _) in fields {
+                        if !columns.contains(&key) {
+                            columns.push(key);
+                        }
+                    }
+                }
+            }
+            Ok(columns)
+        }
+    }
 }
 
 // add std::io::Read to make unit tessts easier to write without needing external files
-pub fn transaction_reader_from(verbose: bool, rdr: Box<dyn std::io::Read>) -> Result<(BTreeMap<u32, Transaction>, Vec<Transaction>, Vec<Transaction>, Vec<Transaction>), Box<dyn Error>> {
+pub fn transaction_reader_from(verbose: bool, rdr: Box<dyn std::io::Read>) -> Result<TransactionReader, ReadError> {
     let mut rb = ReaderBuilder::new();
     let mut rdr = rb
         .flexible(true) // needed to allow optional amount column at end
         .trim(Trim::All)// needed to enable field parsing
         .from_reader(rdr);
-    let it = rdr.deserialize();
-    let mut dispute_result: Vec<Transaction> = Vec::new();
-    let mut resolve_result: Vec<Transaction> = Vec::new();
-    let mut chargeback_result: Vec<Transaction> = Vec::new();
-    let mut wd_result = BTreeMap::<u32, Transaction>::new();
-    for record in it {
-        let mut trans: Transaction = match record {
-            Ok(t) => t,
-            Err(e) => return Err(Box::new(e)),
-        };
-        let tid = match trans.tx {
+    let headers = rdr.headers()?.clone();
+    Ok(TransactionReader {
+        verbose,
+        precision: PrecisionTable::default(),
+        rdr,
+        headers,
+        record: StringRecord::new(),
+    })
+}
+
+impl TransactionReader {
+    // amounts are rounded or rejected by the places of their asset instead of the default places
+    pub fn with_precision(mut self, precision: PrecisionTable) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    // line in the input of the record most recently returned by next
+    pub fn line(&self) -> Option<u64> {
+        self.record.position().map(|p| p.line())
+    }
+}
+
+impl Iterator for TransactionReader {
+    type Item = TransactionResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.rdr.read_record(&mut self.record) {
+            Ok(true) => {},
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e.into())),
+        }
+        Some(transaction_from_record(self.verbose, &self.precision, &self.headers, &self.record, self.line()))
+    }
+}
+
+// the columns of a row a rejection reports, in this order, whichever order the input has them in
+const REPORTED_COLUMNS: [&str; 6] = ["type", "client", "tx", "amount", "asset", "destination"];
+
+// true when column is the reported column wanted, the asset is also accepted as currency
+fn is_column(column: &str, wanted: &str) -> bool {
+    column == wanted || (wanted == "asset" && column == "currency")
+}
+
+// the reported column values of a csv record as text, by header name. a header without the names keeps
+// type, client, tx and amount in the first four places
+fn record_fields(headers: &StringRecord, record: &StringRecord) -> Vec<String> {
+    REPORTED_COLUMNS.iter().enumerate().map(|(i, wanted)| {
+        let idx = headers.iter().position(|column| is_column(column, wanted)).or(Some(i).filter(|i| *i < 4));
+        idx.and_then(|idx| record.get(idx)).map(String::from).unwrap_or_default()
+    }).collect()
+}
+
+// turns one csv record into a transaction with the same validation and rounding whatever the record came from
+pub fn transaction_from_record(verbose: bool, precision: &PrecisionTable, headers: &StringRecord, record: &StringRecord, line: Option<u64>) -> TransactionResult {
+    let fields = || record_fields(headers, record);
+    let trans = match record.deserialize::<Transaction>(Some(headers)) {
+        Ok(t) => t,
+        Err(e) => return Err(ReadError::MalformedRow { line, fields: fields(), source: Box::new(e) }),
+    };
+    prepare(verbose, precision, trans, line, fields)
+}
+
+// validation and rounding shared by every input format
+fn prepare(verbose: bool, precision: &PrecisionTable, mut trans: Transaction, line: Option<u64>, fields: impl Fn() -> Vec<String>) -> TransactionResult {
+    if trans.tx.is_none() {
+        return Err(ReadError::MissingTransactionId { line, fields: fields() });
+    }
+    // a blank asset is the default asset, same as leaving the column out
+    if trans.asset.as_deref().is_some_and(|asset| asset.trim().is_empty()) {
+        trans.asset = None;
+    }
+    let max_places = precision.places(trans.asset.as_deref());
+    let original_amount = trans.amount.unwrap_or_default();
+    if original_amount.scale() > max_places {
+        let original_scale = original_amount.scale();
+        // trailing zeros are not extra precision
+        let places = original_amount.normalize().scale();
+        let rounded_amount = match precision.rounding().round(original_amount, max_places) {
             Some(v) => v,
-            None => return Err("No transaction id".into())
+            None if places > max_places => return Err(ReadError::TooPrecise { line, fields: fields(), places, max: max_places }),
+            None => original_amount.round_dp(max_places),
         };
-        let original_amount = match trans.amount {
-            Some(a) => a,
-            None => Decimal::new(0,0)
-        };    
-        if original_amount.scale() > MAX_DECIMAL_PLACES {
-            let original_scale = original_amount.scale();
-            let rounded_amount = original_amount.round_dp(MAX_DECIMAL_PLACES);
-            trans.amount = Some(rounded_amount);
-            if verbose {
-                eprintln!("amount scale permitted exceeded max decimal places:[{:?}] will round :[original amount:[{:?}] original scale:[{:?}] new amount:[{:?}] new scale:[{:?}]]"
-                    ,MAX_DECIMAL_PLACES,original_amount,original_scale,rounded_amount,rounded_amount.scale());
-            }                          
+        trans.amount = Some(rounded_amount);
+        if rounded_amount != original_amount {
+            trans.rounded_from = Some(original_amount);
         }
         if verbose {
-            let verbose_amount = match trans.amount {
-                Some(a) => a,
-                None => Decimal::new(0,0)
-            };    
-            eprintln!("transaction:[{:?} amount scale:[{:?}]]",trans,verbose_amount.scale());
+            eprintln!("amount scale permitted exceeded max decimal places:[{:?}] will round :[original amount:[{:?}] original scale:[{:?}] new amount:[{:?}] new scale:[{:?}]]"
+                ,max_places,original_amount,original_scale,rounded_amount,rounded_amount.scale());
         }
-        match trans.transaction_type {
-            crate::TransactionType::Deposit => {
-                wd_result.insert(tid, trans);
-            },
-            crate::TransactionType::Withdrawal => {
-                wd_result.insert(tid, trans);
-            }
-            crate::TransactionType::Dispute => {
-                dispute_result.push(trans);
-            }
-            crate::TransactionType::Resolve => {
-                resolve_result.push(trans);
-            }
-            crate::TransactionType::Chargeback => {
-                chargeback_result.push(trans);
+    }
+    trans.line = line;
+    if verbose {
+        let verbose_amount = trans.amount.unwrap_or_default();
+        eprintln!("transaction:[{:?} amount scale:[{:?}]]",trans,verbose_amount.scale());
+    }
+    Ok(trans)
+}
+
+// one json object per line with the same type, client, tx and amount fields as the csv columns
+pub struct JsonlTransactionReader {
+    verbose: bool,
+    precision: PrecisionTable,
+    lines: Lines<BufReader<Box<dyn std::io::Read>>>,
+    line: u64,
+}
+
+pub fn jsonl_reader_from(verbose: bool, rdr: Box<dyn std::io::Read>) -> JsonlTransactionReader {
+    JsonlTransactionReader {
+        verbose,
+        precision: PrecisionTable::default(),
+        lines: BufReader::new(rdr).lines(),
+        line: 0,
+    }
+}
+
+impl JsonlTransactionReader {
+    // see TransactionReader::with_precision
+    pub fn with_precision(mut self, precision: PrecisionTable) -> Self {
+        self.precision = precision;
+        self
+    }
+}
+
+impl Iterator for JsonlTransactionReader {
+    type Item = TransactionResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let text = match self.lines.next()? {
+                Ok(text) => text,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.line += 1;
+            if text.trim().is_empty() {
+                continue;
             }
+            let line = Some(self.line);
+            let fields = || jsonl_fields(&text);
+            return Some(match serde_json::from_str::<Transaction>(&text) {
+                Ok(trans) => prepare(self.verbose, &self.precision, trans, line, fields),
+                Err(e) => Err(ReadError::MalformedRow { line, fields: fields(), source: Box::new(e) }),
+            });
         }
     }
-    Ok((wd_result, dispute_result, resolve_result, chargeback_result))
-}
\ No newline at end of file
+}
+
+// the reported column values of a json line as text in the same order as record_fields, for reporting a rejected line
+fn jsonl_fields(text: &str) -> Vec<String> {
+    let value = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(serde_json::Value::Object(v)) => v,
+        _ => return vec![text.to_string()],
+    };
+    REPORTED_COLUMNS.iter().map(|wanted| match value.iter().find(|(key, _)| is_column(key, wanted)).map(|(_, v)| v) {
+        Some(serde_json::Value::String(v)) => v.clone(),
+        Some(serde_json::Value::Null) | None => String::new(),
+        Some(v) => v.to_string(),
+    }).collect()
+}
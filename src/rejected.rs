@@ -0,0 +1,187 @@
+use crate::{Transaction, error::{LedgerError, ReadError}};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::{self, Write};
+
+// a row that was rejected or ignored, or whose amount was rounded, keeps what the partner sent so it can go back
+// to them in an exceptions file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Rejection {
+    pub line: Option<u64>,
+    #[serde(rename = "type")]
+    pub transaction_type: String,
+    pub client: String,
+    pub tx: String,
+    pub amount: String,
+    pub reason: &'static str,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset: Option<String>, // none for the default asset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>, // only for rows that name a destination client
+}
+
+impl Rejection {
+    pub fn from_transaction(trans: &Transaction, e: &LedgerError) -> Self {
+        Self {
+            line: trans.line,
+            transaction_type: trans.transaction_type.as_str().to_string(),
+            client: trans.client.map(|v| v.to_string()).unwrap_or_default(),
+            tx: trans.tx.map(|v| v.to_string()).unwrap_or_default(),
+            amount: trans.amount.map(|v| v.to_string()).unwrap_or_default(),
+            reason: e.code(),
+            detail: e.to_string(),
+            asset: trans.asset.clone(),
+            destination: trans.destination.map(|v| v.to_string()),
+        }
+    }
+
+    // an accepted row whose amount was rounded when read, amount is what the partner sent and detail holds the
+    // rounded amount and the adjustment rounding made
+    pub fn from_rounding(trans: &Transaction, original: Decimal) -> Self {
+        let rounded = trans.amount.unwrap_or_default();
+        Self {
+            line: trans.line,
+            transaction_type: trans.transaction_type.as_str().to_string(),
+            client: trans.client.map(|v| v.to_string()).unwrap_or_default(),
+            tx: trans.tx.map(|v| v.to_string()).unwrap_or_default(),
+            amount: original.to_string(),
+            reason: "rounded",
+            detail: format!("amount:[{}] rounded to:[{}] adjustment:[{}]", original, rounded, rounded - original),
+            asset: trans.asset.clone(),
+            destination: trans.destination.map(|v| v.to_string()),
+        }
+    }
+
+    // the row as the partner sent it with an empty reason, for reports that list accepted rows next to rejected ones
+    pub fn from_accepted(trans: &Transaction) -> Self {
+        Self {
+            line: trans.line,
+            transaction_type: trans.transaction_type.as_str().to_string(),
+            client: trans.client.map(|v| v.to_string()).unwrap_or_default(),
+            tx: trans.tx.map(|v| v.to_string()).unwrap_or_default(),
+            amount: trans.amount.map(|v| v.to_string()).unwrap_or_default(),
+            reason: "",
+            detail: String::new(),
+            asset: trans.asset.clone(),
+            destination: trans.destination.map(|v| v.to_string()),
+        }
+    }
+
+    pub fn from_read_error(e: &ReadError) -> Self {
+        let fields = match e {
+            ReadError::MalformedRow { fields, .. } => fields.as_slice(),
+            ReadError::MissingTransactionId { fields, .. } => fields.as_slice(),
+            ReadError::TooPrecise { fields, .. } => fields.as_slice(),
+            ReadError::Io(_) => &[][..],
+        };
+        // fields are type, client, tx, amount, asset and destination, the last two only when given
+        let field = |i: usize| fields.get(i).cloned().unwrap_or_default();
+        let optional = |i: usize| Some(field(i)).filter(|v| !v.trim().is_empty());
+        Self {
+            line: e.line(),
+            transaction_type: field(0),
+            client: field(1),
+            tx: field(2),
+            amount: field(3),
+            reason: e.code(),
+            detail: e.to_string(),
+            asset: optional(4),
+            destination: optional(5),
+        }
+    }
+
+    // gives the row the optional columns a csv of rejections was set up with, empty where the row has no
+    // value, and drops the others, so every row of the file or connection has the same columns
+    pub fn fit_columns(&mut self, asset_column: bool, destination_column: bool) {
+        self.asset = if asset_column { Some(self.asset.take().unwrap_or_default()) } else { None };
+        self.destination = if destination_column { Some(self.destination.take().unwrap_or_default()) } else { None };
+    }
+}
+
+// receives rejections as they happen so a large run never has to hold them all in memory
+pub trait RejectionSink {
+    fn reject(&mut self, rejection: Rejection) -> io::Result<()>;
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl RejectionSink for Vec<Rejection> {
+    fn reject(&mut self, rejection: Rejection) -> io::Result<()> {
+        self.push(rejection);
+        Ok(())
+    }
+}
+
+// used when no rejected report was asked for
+pub struct IgnoreRejections;
+
+impl RejectionSink for IgnoreRejections {
+    fn reject(&mut self, _rejection: Rejection) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct CsvRejectionWriter {
+    wtr: csv::Writer<Box<dyn Write>>,
+    asset_column: bool,
+    destination_column: bool,
+}
+
+impl CsvRejectionWriter {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self { wtr: csv::Writer::from_writer(out), asset_column: false, destination_column: false }
+    }
+
+    // every row gets the asset column, empty for the default asset, so the file keeps one set of columns
+    // whichever row comes first. without it the asset is left out
+    pub fn with_asset_column(mut self, asset_column: bool) -> Self {
+        self.asset_column = asset_column;
+        self
+    }
+
+    // same for the destination, which comes after the asset
+    pub fn with_destination_column(mut self, destination_column: bool) -> Self {
+        self.destination_column = destination_column;
+        self
+    }
+}
+
+impl RejectionSink for CsvRejectionWriter {
+    fn reject(&mut self, mut rejection: Rejection) -> io::Result<()> {
+        rejection.fit_columns(self.asset_column, self.destination_column);
+        self.wtr.serialize(rejection)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.wtr.flush()
+    }
+}
+
+// writes a single json array, the closing bracket is only written by finish
+pub struct JsonRejectionWriter {
+    out: Box<dyn Write>,
+    count: u64,
+}
+
+impl JsonRejectionWriter {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self { out, count: 0 }
+    }
+}
+
+impl RejectionSink for JsonRejectionWriter {
+    fn reject(&mut self, rejection: Rejection) -> io::Result<()> {
+        self.out.write_all(if self.count == 0 { b"[\n" } else { b",\n" })?;
+        serde_json::to_writer(&mut self.out, &rejection)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.write_all(if self.count == 0 { b"[]\n" } else { b"\n]\n" })?;
+        self.out.flush()
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{AccountStatus, MAX_DECIMAL_PLACES, RunSummary, precision::PrecisionTable};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::{self, Write};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+    Jsonl,
+}
+
+impl ReportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(ReportFormat::Csv),
+            "json" => Some(ReportFormat::Json),
+            "jsonl" => Some(ReportFormat::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+// asset is only written when some account is in an asset other than the default, and authorized only when
+// some account has funds reserved by authorizations, so reports without either keep their columns
+#[derive(Debug, Serialize)]
+struct ReportRow {
+    client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asset: Option<String>,
+    available: String,
+    held: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authorized: Option<String>,
+    total: String,
+    locked: bool,
+}
+
+impl ReportRow {
+    fn from_account(cas: &AccountStatus, multi_asset: bool, authorizations: bool, precision: &PrecisionTable) -> Self {
+        let places = precision.places(cas.asset.as_deref());
+        Self {
+            client: cas.client,
+            asset: if multi_asset { Some(cas.asset.clone().unwrap_or_default()) } else { None },
+            available: format_amount_to(cas.available, places),
+            held: format_amount_to(cas.held, places),
+            authorized: if authorizations { Some(format_amount_to(cas.authorized, places)) } else { None },
+            total: format_amount_to(cas.total, places),
+            locked: cas.locked,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JsonReport {
+    accounts: Vec<ReportRow>,
+    summary: RunSummary,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonlSummary {
+    summary: RunSummary,
+}
+
+// always render MAX_DECIMAL_PLACES places so the same balances produce the same bytes
+pub fn format_amount(amount: Decimal) -> String {
+    format_amount_to(amount, MAX_DECIMAL_PLACES)
+}
+
+pub fn format_amount_to(amount: Decimal, places: u32) -> String {
+    let mut fixed = amount.round_dp(places);
+    fixed.rescale(places);
+    fixed.to_string()
+}
+
+// one row per account, with the account's asset when there are several
+fn report_rows(accounts: &mut dyn Iterator<Item = &AccountStatus>, precision: &PrecisionTable) -> Vec<ReportRow> {
+    let accounts: Vec<&AccountStatus> = accounts.collect();
+    let multi_asset = accounts.iter().any(|cas| cas.asset.is_some());
+    let authorizations = accounts.iter().any(|cas| !cas.authorized.is_zero());
+    accounts.into_iter().map(|cas| ReportRow::from_account(cas, multi_asset, authorizations, precision)).collect()
+}
+
+// accounts are written in the order given, callers pass them sorted by client id and asset.
+// balances have the places of their asset in precision
+pub fn write_accounts(out: &mut dyn Write, accounts: &mut dyn Iterator<Item = &AccountStatus>, precision: &PrecisionTable) -> io::Result<()> {
+    let mut wtr = csv::Writer::from_writer(out);
+    for row in report_rows(accounts, precision) {
+        wtr.serialize(row)?;
+    }
+    wtr.flush()
+}
+
+// csv is the plain account report, json is one document holding the accounts and the run summary and
+// jsonl is one account per line followed by a last line holding the summary
+pub fn write_report(out: &mut dyn Write, format: ReportFormat, accounts: &mut dyn Iterator<Item = &AccountStatus>, summary: &RunSummary, precision: &PrecisionTable) -> io::Result<()> {
+    match format {
+        ReportFormat::Csv => write_accounts(out, accounts, precision),
+        ReportFormat::Json => {
+            let report = JsonReport {
+                accounts: report_rows(accounts, precision),
+                summary: *summary,
+            };
+            serde_json::to_writer_pretty(&mut *out, &report)?;
+            out.write_all(b"\n")?;
+            out.flush()
+        },
+        ReportFormat::Jsonl => {
+            for row in report_rows(accounts, precision) {
+                serde_json::to_writer(&mut *out, &row)?;
+                out.write_all(b"\n")?;
+            }
+            serde_json::to_writer(&mut *out, &JsonlSummary { summary: *summary })?;
+            out.write_all(b"\n")?;
+            out.flush()
+        },
+    }
+}
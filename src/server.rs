@@ -0,0 +1,227 @@
+use crate::{AccountStatus, Ledger, Transaction};
+use crate::error::{BankexError, LedgerError};
+use crate::ledger::InMemoryLedger;
+use crate::policy::LockedAccountPolicy;
+use crate::precision::PrecisionTable;
+use crate::read;
+use crate::rejected::Rejection;
+use crate::report;
+use crate::sharded;
+use csv::{ReaderBuilder, StringRecord, Trim, WriterBuilder};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+// line a connection sends to get the current account report instead of a transaction row
+pub const REPORT_COMMAND: &str = "report";
+
+// one ledger shared by every connection. accounts are split by client id into shards that each sit
+// behind their own lock, so transactions for one client are applied one at a time in the order they
+// arrive while different clients proceed in parallel. a row that reaches into another shard, a transfer
+// between clients of different shards or its dispute, or a row whose tx id a client of another shard
+// already used, holds every shard it touches while it is applied, so it is checked as on a single ledger
+pub struct SharedLedger {
+    shards: Vec<Mutex<InMemoryLedger>>,
+    owners: Mutex<HashMap<u32, usize>>, // shard of every claimed tx id, only locked while a shard is held
+    precision: PrecisionTable, // rows are read and the report written with the places of each asset
+}
+
+impl SharedLedger {
+    pub fn new(shards: usize, precision: PrecisionTable) -> Self {
+        let shards = (0..shards.max(1)).map(|_| Mutex::new(InMemoryLedger::default())).collect();
+        Self { shards, owners: Mutex::new(HashMap::new()), precision }
+    }
+
+    pub fn precision(&self) -> &PrecisionTable {
+        &self.precision
+    }
+
+    pub fn set_locked_policy(&self, policy: LockedAccountPolicy) {
+        for shard in &self.shards {
+            shard.lock().expect("ledger shard lock poisoned").set_locked_policy(policy.clone());
+        }
+    }
+
+    pub fn set_authorization_expiry(&self, transactions: u64) {
+        for shard in &self.shards {
+            shard.lock().expect("ledger shard lock poisoned").set_authorization_expiry(transactions);
+        }
+    }
+
+    fn shard_of(&self, client: Option<u16>) -> usize {
+        client.unwrap_or_default() as usize % self.shards.len()
+    }
+
+    // the shards besides home that trans needs, looked up with shard, the home shard, held. a new tx id is
+    // claimed for home here, so a row of another shard reusing it waits for home and then sees its record
+    fn shards_across(&self, shard: &InMemoryLedger, trans: &Transaction, home: usize) -> Vec<usize> {
+        let mut across: Vec<usize> = sharded::shard_across(shard, trans, self.shards.len()).into_iter().collect();
+        if let Some(tid) = trans.tx {
+            let mut owners = self.owners.lock().expect("tx owners lock poisoned");
+            let owner = match owners.get(&tid) {
+                Some(&idx) => Some(idx),
+                None if sharded::claims_tx(trans) => {
+                    owners.insert(tid, home);
+                    None
+                },
+                None => None,
+            };
+            across.extend(owner.filter(|&idx| idx != home));
+        }
+        across
+    }
+
+    pub fn process_transaction(&self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
+        let count = self.shards.len();
+        let home = self.shard_of(trans.client);
+        let mut held = vec![home];
+        loop {
+            // shards are always locked in index order, so rows reaching across in opposite directions can not deadlock
+            held.sort_unstable();
+            held.dedup();
+            let mut guards: Vec<MutexGuard<InMemoryLedger>> = held.iter()
+                .map(|&idx| self.shards[idx].lock().expect("ledger shard lock poisoned"))
+                .collect();
+            let pos = held.iter().position(|&idx| idx == home).expect("home shard not held");
+            let across = self.shards_across(&guards[pos], trans, home);
+            if !across.iter().all(|idx| held.contains(idx)) {
+                // what the row needs may have changed while no shard was held, so look again with all of them
+                held.extend(across);
+                continue;
+            }
+            if held.len() == 1 {
+                return guards[pos].process_transaction(verbose, trans);
+            }
+            let (before, rest) = guards.split_at_mut(pos);
+            let (shard, after) = rest.split_first_mut().expect("home shard out of range");
+            let mut others: Vec<(usize, &mut InMemoryLedger)> = held[..pos].iter().copied().zip(before.iter_mut().map(|g| &mut **g))
+                .chain(held[pos + 1..].iter().copied().zip(after.iter_mut().map(|g| &mut **g)))
+                .collect();
+            let result = sharded::apply_across(verbose, trans, false, count, shard, &mut others);
+            // a tx id claimed by a row that was then rejected now belongs to the row that stored it
+            if let Some(tid) = trans.tx.filter(|tid| shard.by_transaction_id.contains_key(tid)) {
+                self.owners.lock().expect("tx owners lock poisoned").insert(tid, home);
+            }
+            return result;
+        }
+    }
+
+    // shards are locked one after another, so the report is consistent per client but not a single point in time
+    pub fn run_report(&self, out: &mut dyn Write) -> Result<(), BankexError> {
+        let mut accounts: Vec<AccountStatus> = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.lock().expect("ledger shard lock poisoned");
+            accounts.extend(shard.by_account.values().cloned());
+        }
+        accounts.sort_by_key(|cas| cas.client);
+        report::write_accounts(out, &mut accounts.iter(), &self.precision)?;
+        Ok(())
+    }
+}
+
+pub struct Server {
+    listener: TcpListener,
+    ledger: Arc<SharedLedger>,
+    verbose: bool,
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs, shards: usize, precision: PrecisionTable, verbose: bool) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            ledger: Arc::new(SharedLedger::new(shards, precision)),
+            verbose,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn ledger(&self) -> Arc<SharedLedger> {
+        Arc::clone(&self.ledger)
+    }
+
+    // accepts connections until the listener fails, each one is served on its own thread
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let ledger = Arc::clone(&self.ledger);
+            let verbose = self.verbose;
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = handle_connection(stream, &ledger, verbose) {
+                    if verbose {
+                        eprintln!("connection:[{:?}] closed with error:[{}]",peer,e);
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+// the first line of a connection is the csv header, every following line is a transaction row in the
+// same format as the input file or the report command. nothing is written back for accepted rows, a
+// rejected row is answered with one csv line of line,type,client,tx,amount,reason,detail, followed by
+// asset and destination when the connection's header has those columns, empty where the row has no value,
+// and the report command with the account report followed by an empty line
+pub fn handle_connection(stream: TcpStream, ledger: &SharedLedger, verbose: bool) -> io::Result<()> {
+    let mut out = stream.try_clone()?;
+    let rdr = BufReader::new(stream);
+    let mut headers: Option<StringRecord> = None;
+    for (idx, line) in rdr.lines().enumerate() {
+        let line = line?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if trimmed.eq_ignore_ascii_case(REPORT_COMMAND) {
+            ledger.run_report(&mut out).map_err(|e| io::Error::other(e.to_string()))?;
+            out.write_all(b"\n")?;
+            continue;
+        }
+        let record = match parse_line(&line) {
+            Some(record) => record,
+            None => continue,
+        };
+        let headers = match &headers {
+            Some(h) => h,
+            None => {
+                headers = Some(record);
+                continue;
+            }
+        };
+        let line_number = Some(idx as u64 + 1);
+        let rejection = match read::transaction_from_record(verbose, ledger.precision(), headers, &record, line_number) {
+            Ok(trans) => match ledger.process_transaction(verbose, &trans) {
+                Ok(()) => None,
+                Err(e) => Some(Rejection::from_transaction(&trans, &e)),
+            },
+            Err(e) => Some(Rejection::from_read_error(&e)),
+        };
+        if let Some(mut rejection) = rejection {
+            let has = |name: &str| headers.iter().any(|column| column == name);
+            rejection.fit_columns(has("asset") || has("currency"), has("destination"));
+            let mut rejected = WriterBuilder::new().has_headers(false).from_writer(&mut out);
+            rejected.serialize(rejection)?;
+            rejected.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_line(line: &str) -> Option<StringRecord> {
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true) // needed to allow optional amount column at end
+        .trim(Trim::All)
+        .from_reader(line.as_bytes());
+    let mut record = StringRecord::new();
+    match rdr.read_record(&mut record) {
+        Ok(true) => Some(record),
+        _ => None,
+    }
+}
@@ -0,0 +1,356 @@
+use crate::{AccountKey, AccountStatus, Ledger, RunSummary, Transaction, TransactionType};
+use crate::error::{BankexError, LedgerError, ReadError};
+use crate::ledger::{AccountLoan, InMemoryLedger};
+use crate::read::TransactionResult;
+use crate::rejected::{Rejection, RejectionSink};
+use crate::policy::LockedAccountPolicy;
+use crate::postings::TrialBalance;
+use crate::snapshot;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::mpsc;
+use std::thread;
+
+// transactions are handed to workers in batches to keep channel overhead per row low, and only a few
+// batches may wait per worker so memory stays bounded when one shard falls behind the reader
+const SHARD_BATCH_SIZE: usize = 512;
+const SHARD_QUEUE_DEPTH: usize = 4;
+
+// accounts are independent, so the ledger is split by client id into shards that are each owned by one
+// worker thread while reading. every transaction for a client goes to the same shard in file order,
+// which keeps per client ordering while different clients are processed in parallel.
+// a row that reaches into another shard, a transfer to a client of another shard, its dispute, resolve or
+// chargeback, or a row whose tx id was already used by a client of another shard, waits until the workers
+// are done with every row before it and is applied on the calling thread with every shard at hand, so the
+// result is the same as on a single ledger
+pub struct ShardedLedger {
+    shards: Vec<InMemoryLedger>,
+}
+
+impl ShardedLedger {
+    pub fn new(workers: usize) -> Self {
+        let shards = (0..workers.max(1)).map(|_| InMemoryLedger::default()).collect();
+        Self { shards }
+    }
+
+    pub fn shards(&self) -> &[InMemoryLedger] {
+        &self.shards
+    }
+
+    pub fn set_locked_policy(&mut self, policy: LockedAccountPolicy) {
+        for shard in self.shards.iter_mut() {
+            shard.set_locked_policy(policy.clone());
+        }
+    }
+
+    pub fn set_authorization_expiry(&mut self, transactions: u64) {
+        for shard in self.shards.iter_mut() {
+            shard.set_authorization_expiry(transactions);
+        }
+    }
+
+    fn shard_of(&self, client: Option<u16>) -> usize {
+        client.unwrap_or_default() as usize % self.shards.len()
+    }
+
+    fn shard_for(&self, client: u16) -> &InMemoryLedger {
+        &self.shards[self.shard_of(Some(client))]
+    }
+
+    // every shard is at hand here, so a row is applied the way a single ledger would apply it
+    fn apply(&mut self, verbose: bool, trans: &Transaction, admin: bool) -> Result<(), LedgerError> {
+        let count = self.shards.len();
+        let home = self.shard_of(trans.client);
+        let (before, rest) = self.shards.split_at_mut(home);
+        let (ledger, after) = rest.split_first_mut().expect("home shard out of range");
+        let mut others: Vec<(usize, &mut InMemoryLedger)> = before.iter_mut().enumerate()
+            .chain(after.iter_mut().enumerate().map(|(i, shard)| (home + 1 + i, shard)))
+            .collect();
+        apply_across(verbose, trans, admin, count, ledger, &mut others)
+    }
+
+    // owner of every stored tx id, the dispatching thread keeps it up to date as it hands rows out
+    fn owners(&self) -> HashMap<u32, Owner> {
+        self.shards.iter()
+            .flat_map(|shard| shard.by_transaction_id.iter())
+            .map(|(tid, record)| (*tid, Owner::of(&record.transaction)))
+            .collect()
+    }
+
+    // hands rows to the workers until the input ends or a row reaches into another shard than its client's.
+    // that row is returned once the workers have finished every row before it
+    fn dispatch(&mut self, verbose: bool, transactions: &mut dyn Iterator<Item = TransactionResult>, rejections: &mut dyn RejectionSink, owners: &mut HashMap<u32, Owner>, summary: &mut RunSummary) -> Result<Option<Transaction>, BankexError> {
+        let count = self.shards.len();
+        // the flag tells a rejected row from an accepted one whose rounding goes to the exceptions
+        let (rejected_tx, rejected_rx) = mpsc::channel::<(Rejection, bool)>();
+        thread::scope(|scope| {
+            let mut senders = Vec::with_capacity(count);
+            for shard in self.shards.iter_mut() {
+                let (tx, rx) = mpsc::sync_channel::<Vec<Transaction>>(SHARD_QUEUE_DEPTH);
+                let rejected = rejected_tx.clone();
+                scope.spawn(move || {
+                    for transaction in rx.into_iter().flatten() {
+                        let exception = match shard.process_transaction(verbose, &transaction) {
+                            Ok(()) => transaction.rounded_from.map(|original| (Rejection::from_rounding(&transaction, original), false)),
+                            Err(e) => {
+                                if verbose {
+                                    eprintln!("skipping {:?} transaction reason:[{}]",transaction.tx,e);
+                                }
+                                Some((Rejection::from_transaction(&transaction, &e), true))
+                            }
+                        };
+                        if let Some(exception) = exception {
+                            if rejected.send(exception).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+                senders.push(tx);
+            }
+            drop(rejected_tx);
+            let mut batches: Vec<Vec<Transaction>> = (0..count).map(|_| Vec::with_capacity(SHARD_BATCH_SIZE)).collect();
+            let mut across = None;
+            let mut dispatch = || -> Result<(), BankexError> {
+                for record in &mut *transactions {
+                    summary.processed += 1;
+                    match record {
+                        Ok(t) => {
+                            let idx = t.client.unwrap_or_default() as usize % count;
+                            if Owner::of(&t).reaches_across(count) {
+                                across = Some(t);
+                                break;
+                            }
+                            if let Some(tid) = t.tx {
+                                match owners.get(&tid) {
+                                    Some(owner) if owner.client as usize % count != idx || owner.reaches_across(count) => {
+                                        across = Some(t);
+                                        break;
+                                    },
+                                    Some(_) => {},
+                                    None if claims_tx(&t) => {
+                                        owners.insert(tid, Owner::of(&t));
+                                    },
+                                    None => {},
+                                }
+                            }
+                            batches[idx].push(t);
+                            if batches[idx].len() == SHARD_BATCH_SIZE {
+                                let batch = std::mem::replace(&mut batches[idx], Vec::with_capacity(SHARD_BATCH_SIZE));
+                                if senders[idx].send(batch).is_err() {
+                                    break; // worker is gone, its panic surfaces when the scope joins
+                                }
+                            }
+                        },
+                        Err(ReadError::Io(e)) => return Err(ReadError::Io(e).into()),
+                        Err(e) => {
+                            if verbose {
+                                eprintln!("skipping row reason:[{}]",e);
+                            }
+                            summary.rejected += 1;
+                            rejections.reject(Rejection::from_read_error(&e))?;
+                        }
+                    }
+                    for (rejection, rejected) in rejected_rx.try_iter() {
+                        summary.rejected += rejected as u64;
+                        rejections.reject(rejection)?;
+                    }
+                }
+                for (sender, batch) in senders.iter().zip(batches.drain(..)) {
+                    let _ = sender.send(batch);
+                }
+                Ok(())
+            };
+            let result = dispatch();
+            drop(senders); // lets the workers run out of input and exit
+            result?;
+            for (rejection, rejected) in rejected_rx.iter() {
+                summary.rejected += rejected as u64;
+                rejections.reject(rejection)?;
+            }
+            Ok(across)
+        })
+    }
+}
+
+// the client a tx id belongs to and, for a transfer, the destination whose account holds its disputes
+struct Owner {
+    client: u16,
+    destination: Option<u16>,
+}
+
+impl Owner {
+    fn of(trans: &Transaction) -> Self {
+        Self {
+            client: trans.client.unwrap_or_default(),
+            destination: trans.destination.filter(|_| trans.transaction_type == TransactionType::Transfer),
+        }
+    }
+
+    // a transfer to a client of another shard out of count, its disputes change an account of that shard too
+    fn reaches_across(&self, count: usize) -> bool {
+        self.destination.is_some_and(|destination| destination as usize % count != self.client as usize % count)
+    }
+}
+
+// disputes, resolves, chargebacks, captures and voids only refer to a tx id, every other row stores one
+pub(crate) fn claims_tx(trans: &Transaction) -> bool {
+    !matches!(trans.transaction_type, TransactionType::Dispute | TransactionType::Resolve
+        | TransactionType::Chargeback | TransactionType::Capture | TransactionType::Void)
+}
+
+// accounts of other shards than the one of its client that trans changes, looked up on the client's shard
+fn accounts_across(ledger: &InMemoryLedger, trans: &Transaction, count: usize) -> Vec<AccountKey> {
+    let home = trans.client.unwrap_or_default() as usize % count;
+    ledger.account_key(trans).into_iter().chain(ledger.counterparty_key(trans))
+        .filter(|key| key.0 as usize % count != home)
+        .collect()
+}
+
+// the other shard out of count a transaction changes an account of, when ledger is the shard of its client
+pub(crate) fn shard_across(ledger: &InMemoryLedger, trans: &Transaction, count: usize) -> Option<usize> {
+    accounts_across(ledger, trans, count).first().map(|key| key.0 as usize % count)
+}
+
+// applies trans on ledger, the shard of its client, while it reaches into others, the other shards with their
+// index out of count. the accounts of other shards the row changes are lent to ledger for the duration, and
+// so is a record of its tx id another shard holds, so the row sees what a single ledger would see: a reused
+// tx id is a duplicate and a dispute naming another client's tx a client mismatch
+pub(crate) fn apply_across(verbose: bool, trans: &Transaction, admin: bool, count: usize, ledger: &mut InMemoryLedger, others: &mut [(usize, &mut InMemoryLedger)]) -> Result<(), LedgerError> {
+    let mut loans = Vec::new();
+    for key in accounts_across(ledger, trans, count) {
+        if let Some(pos) = others.iter().position(|(idx, _)| *idx == key.0 as usize % count) {
+            let mut loan = AccountLoan::new(key);
+            others[pos].1.lend(&mut loan);
+            ledger.receive(&mut loan);
+            loans.push((pos, loan));
+        }
+    }
+    let mut borrowed = None;
+    if let Some(tid) = trans.tx.filter(|tid| !ledger.by_transaction_id.contains_key(tid)) {
+        if let Some(record) = others.iter().find_map(|(_, shard)| shard.by_transaction_id.get(&tid)) {
+            ledger.by_transaction_id.insert(tid, record.clone());
+            borrowed = Some(tid);
+        }
+    }
+    let result = if admin { ledger.process_admin(verbose, trans) } else { ledger.process_transaction(verbose, trans) };
+    if let Some(tid) = borrowed {
+        ledger.by_transaction_id.remove(&tid);
+    }
+    for (pos, mut loan) in loans {
+        ledger.lend(&mut loan);
+        others[pos].1.receive(&mut loan);
+    }
+    result
+}
+
+impl Ledger for ShardedLedger {
+    fn process_transaction(&mut self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
+        self.apply(verbose, trans, false)
+    }
+
+    fn process_admin(&mut self, verbose: bool, trans: &Transaction) -> Result<(), LedgerError> {
+        self.apply(verbose, trans, true)
+    }
+
+    fn get_funds_available(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        self.shard_for(client_id).get_funds_available(client_id)
+    }
+
+    fn get_funds_held(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        self.shard_for(client_id).get_funds_held(client_id)
+    }
+
+    fn get_funds_total(&self, client_id: u16) -> Result<Decimal, LedgerError> {
+        self.shard_for(client_id).get_funds_total(client_id)
+    }
+
+    fn verify_transaction(&self, trans: &Transaction) -> Result<(), LedgerError> {
+        self.shards[self.shard_of(trans.client)].verify_transaction(trans)
+    }
+
+    // the calling thread reads the input and hands each transaction to its shard's worker, rejections
+    // come back over a channel so the sink is only ever written from the calling thread. a row that reaches
+    // into another shard is applied on the calling thread between two rounds of workers
+    fn read_transactions(&mut self, verbose: bool, transactions: &mut dyn Iterator<Item = TransactionResult>, rejections: &mut dyn RejectionSink) -> Result<RunSummary, BankexError> {
+        let mut summary = RunSummary::default();
+        let mut owners = self.owners();
+        while let Some(trans) = self.dispatch(verbose, transactions, rejections, &mut owners, &mut summary)? {
+            match self.apply(verbose, &trans, false) {
+                Ok(()) => {
+                    if let Some(original) = trans.rounded_from {
+                        rejections.reject(Rejection::from_rounding(&trans, original))?;
+                    }
+                },
+                Err(e) => {
+                    if verbose {
+                        eprintln!("skipping {:?} transaction reason:[{}]",trans.tx,e);
+                    }
+                    summary.rejected += 1;
+                    rejections.reject(Rejection::from_transaction(&trans, &e))?;
+                }
+            }
+            // every row so far is applied, so the tx id belongs to whoever stored it now
+            if let Some(tid) = trans.tx {
+                match self.shards.iter().find_map(|shard| shard.by_transaction_id.get(&tid)) {
+                    Some(record) => owners.insert(tid, Owner::of(&record.transaction)),
+                    None => owners.remove(&tid),
+                };
+            }
+        }
+        summary.accepted = summary.processed - summary.rejected;
+        Ok(summary)
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = &AccountStatus> + '_> {
+        let mut accounts: Vec<&AccountStatus> = self.shards.iter().flat_map(|s| s.by_account.values()).collect();
+        accounts.sort_by_key(|cas| cas.client);
+        Box::new(accounts.into_iter())
+    }
+
+    fn account_of(&self, trans: &Transaction) -> Option<&AccountStatus> {
+        self.shards[self.shard_of(trans.client)].account_of(trans)
+    }
+
+    fn counterparty_of(&self, trans: &Transaction) -> Option<&AccountStatus> {
+        self.shards[self.shard_of(trans.client)].counterparty_of(trans)
+    }
+
+    fn trial_balance(&self) -> TrialBalance {
+        let mut trial_balance = TrialBalance::default();
+        for shard in &self.shards {
+            trial_balance.merge(shard.trial_balance());
+        }
+        trial_balance
+    }
+
+    fn save_snapshot(&self, out: &mut dyn Write) -> Result<(), LedgerError> {
+        snapshot::write_snapshot(
+            &mut self.shards.iter().flat_map(|s| s.by_account.values()),
+            &mut self.shards.iter().flat_map(|s| s.by_transaction_id.values()),
+            out)
+    }
+
+    // a snapshot taken with any number of workers can be loaded into any other number
+    fn load_snapshot(&mut self, rdr: &mut dyn Read) -> Result<(), LedgerError> {
+        let restored = snapshot::read_snapshot(rdr)?;
+        // the shards are refilled rather than replaced so their locked policy and expiry stay as configured
+        for shard in self.shards.iter_mut() {
+            shard.by_account.clear();
+            shard.by_transaction_id.clear();
+        }
+        let count = self.shards.len();
+        for (key, cas) in restored.by_account {
+            self.shards[key.0 as usize % count].by_account.insert(key, cas);
+        }
+        for (tid, record) in restored.by_transaction_id {
+            let idx = record.transaction.client.unwrap_or_default() as usize % count;
+            self.shards[idx].by_transaction_id.insert(tid, record);
+        }
+        for shard in self.shards.iter_mut() {
+            shard.rebuild_postings();
+        }
+        Ok(())
+    }
+}
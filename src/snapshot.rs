@@ -0,0 +1,57 @@
+use crate::{AccountStatus, TransactionRecord};
+use crate::error::LedgerError;
+use crate::ledger::InMemoryLedger;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+// bump when the layout changes, load refuses any other version rather than guessing
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+struct SnapshotRef<'a> {
+    version: u32,
+    accounts: Vec<&'a AccountStatus>,
+    transactions: Vec<&'a TransactionRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Snapshot {
+    version: u32,
+    accounts: Vec<AccountStatus>,
+    transactions: Vec<TransactionRecord>,
+}
+
+// accounts and transactions are sorted by key so the same state always gives the same file
+pub fn write_snapshot<'a>(accounts: &mut dyn Iterator<Item = &'a AccountStatus>, transactions: &mut dyn Iterator<Item = &'a TransactionRecord>, out: &mut dyn Write) -> Result<(), LedgerError> {
+    let mut accounts: Vec<&AccountStatus> = accounts.collect();
+    accounts.sort_by_key(|cas| cas.key());
+    let mut transactions: Vec<&TransactionRecord> = transactions.collect();
+    transactions.sort_by_key(|r| r.transaction.tx);
+    let snapshot = SnapshotRef {
+        version: SNAPSHOT_VERSION,
+        accounts,
+        transactions,
+    };
+    serde_json::to_writer(&mut *out, &snapshot).map_err(std::io::Error::from)?;
+    out.write_all(b"\n")?;
+    out.flush()?;
+    Ok(())
+}
+
+pub fn read_snapshot(rdr: &mut dyn Read) -> Result<InMemoryLedger, LedgerError> {
+    let snapshot: Snapshot = serde_json::from_reader(rdr)
+        .map_err(|e| LedgerError::Storage { reason: format!("unreadable snapshot: {}", e) })?;
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(LedgerError::UnsupportedSnapshot { version: snapshot.version, expected: SNAPSHOT_VERSION });
+    }
+    let mut ledger = InMemoryLedger::default();
+    for cas in snapshot.accounts {
+        ledger.by_account.insert(cas.key(), cas);
+    }
+    for record in snapshot.transactions {
+        let tid = record.transaction.tx.unwrap_or_default();
+        ledger.by_transaction_id.insert(tid, record);
+    }
+    ledger.rebuild_postings();
+    Ok(ledger)
+}
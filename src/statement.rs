@@ -0,0 +1,155 @@
+use crate::{AccountStatus, Ledger};
+use crate::error::{BankexError, ReadError};
+use crate::read::TransactionResult;
+use crate::rejected::Rejection;
+use crate::precision::PrecisionTable;
+use crate::report::format_amount_to;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::Write;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StatementFormat {
+    Csv,
+    Text,
+}
+
+impl StatementFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(StatementFormat::Csv),
+            "text" => Some(StatementFormat::Text),
+            _ => None,
+        }
+    }
+}
+
+// one input row of the client with the account of the row's asset right after it, a rejected row leaves the
+// balances as they were. balances have the decimal places of the account's asset, the asset is only written
+// when some line of the statement is in an asset other than the default, and authorized as in the report
+// only when some line's account has funds reserved by authorizations
+#[derive(Debug, Serialize, PartialEq, Clone)]
+pub struct StatementLine {
+    pub line: Option<u64>,
+    #[serde(rename = "type")]
+    pub transaction_type: String,
+    pub tx: String,
+    pub amount: String,
+    pub status: &'static str,
+    pub reason: &'static str,
+    pub available: String,
+    pub held: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorized: Option<String>,
+    pub total: String,
+    pub locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset: Option<String>,
+}
+
+impl StatementLine {
+    fn new(row: Rejection, accepted: bool, account: Option<&AccountStatus>, places: u32) -> Self {
+        let (available, held, authorized, total, locked) = match account {
+            Some(cas) => (cas.available, cas.held, cas.authorized, cas.total, cas.locked),
+            None => Default::default(),
+        };
+        let asset = match account {
+            Some(cas) => cas.asset.clone(),
+            None => row.asset,
+        };
+        Self {
+            line: row.line,
+            transaction_type: row.transaction_type,
+            tx: row.tx,
+            amount: row.amount,
+            status: if accepted { "accepted" } else { "rejected" },
+            reason: row.reason,
+            available: format_amount_to(available, places),
+            held: format_amount_to(held, places),
+            authorized: if authorized.is_zero() { None } else { Some(format_amount_to(authorized, places)) },
+            total: format_amount_to(total, places),
+            locked,
+            asset,
+        }
+    }
+}
+
+// every row of the input goes through the ledger exactly as in a normal run, so the statement shows what
+// processing did, but only the rows naming the client are written. rows that could not be read are
+// included when their client field names the client
+pub fn write_statement(verbose: bool, client: u16, ledger: &mut dyn Ledger, transactions: &mut dyn Iterator<Item = TransactionResult>, format: StatementFormat, precision: &PrecisionTable, out: &mut dyn Write) -> Result<(), BankexError> {
+    let mut lines = statement_lines(verbose, client, ledger, transactions, precision).collect::<Result<Vec<_>, _>>()?;
+    let multi_asset = lines.iter().any(|line| line.asset.is_some());
+    let authorizations = lines.iter().any(|line| line.authorized.is_some());
+    if authorizations {
+        for line in lines.iter_mut() {
+            let places = precision.places(line.asset.as_deref());
+            line.authorized.get_or_insert_with(|| format_amount_to(Decimal::new(0, 0), places));
+        }
+    }
+    if multi_asset {
+        for line in lines.iter_mut() {
+            line.asset.get_or_insert_with(String::new);
+        }
+    }
+    match format {
+        StatementFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(&mut *out);
+            for line in lines {
+                wtr.serialize(line).map_err(std::io::Error::from)?;
+            }
+            wtr.flush()?;
+        },
+        StatementFormat::Text => {
+            let asset = |asset: &str| if multi_asset { format!("  {:<6}", asset) } else { String::new() };
+            let authorized = |authorized: &str| if authorizations { format!("  {:>14}", authorized) } else { String::new() };
+            writeln!(out, "statement for client {}", client)?;
+            writeln!(out, "{:>8}  {:<10}  {:>10}  {:>14}  {:<8}  {:>14}  {:>14}{}  {:>14}  {:<6}{}  reason",
+                "line", "type", "tx", "amount", "status", "available", "held", authorized("authorized"), "total", "locked", asset("asset"))?;
+            for line in lines {
+                let text = format!("{:>8}  {:<10}  {:>10}  {:>14}  {:<8}  {:>14}  {:>14}{}  {:>14}  {:<6}{}  {}",
+                    line.line.map(|v| v.to_string()).unwrap_or_default(), line.transaction_type, line.tx, line.amount,
+                    line.status, line.available, line.held, authorized(line.authorized.as_deref().unwrap_or_default()), line.total,
+                    line.locked, asset(line.asset.as_deref().unwrap_or_default()), line.reason);
+                writeln!(out, "{}", text.trim_end())?;
+            }
+        },
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// the statement lines of the client in input order, processing stops at the first error reading the input
+pub fn statement_lines<'a>(verbose: bool, client: u16, ledger: &'a mut dyn Ledger, transactions: &'a mut dyn Iterator<Item = TransactionResult>, precision: &'a PrecisionTable) -> Box<dyn Iterator<Item = Result<StatementLine, BankexError>> + 'a> {
+    let wanted = client.to_string();
+    Box::new(transactions.filter_map(move |record| {
+        let (rejection, accepted, account) = match record {
+            Ok(trans) => {
+                let result = ledger.process_transaction(verbose, &trans);
+                if trans.client != Some(client) && trans.destination != Some(client) {
+                    return None;
+                }
+                // a transfer shows on both sides, each with the client's own account
+                let account = ledger.account_of(&trans).into_iter().chain(ledger.counterparty_of(&trans))
+                    .find(|cas| cas.client == client)
+                    .or_else(|| ledger.account_of(&trans))
+                    .cloned();
+                match result {
+                    Ok(()) => (Rejection::from_accepted(&trans), true, account),
+                    Err(e) => (Rejection::from_transaction(&trans, &e), false, account),
+                }
+            },
+            Err(ReadError::Io(e)) => return Some(Err(ReadError::Io(e).into())),
+            Err(e) => {
+                let rejection = Rejection::from_read_error(&e);
+                if rejection.client != wanted {
+                    return None;
+                }
+                let account = ledger.accounts().find(|cas| cas.client == client).cloned();
+                (rejection, false, account)
+            }
+        };
+        let places = precision.places(account.as_ref().and_then(|cas| cas.asset.as_deref()));
+        Some(Ok(StatementLine::new(rejection, accepted, account.as_ref(), places)))
+    }))
+}
@@ -0,0 +1,67 @@
+use bankex::{Ledger, disk::DiskLedger, read, rejected::IgnoreRejections};
+use rust_decimal::Decimal;
+use std::{fs, io::Write, path::PathBuf};
+
+static VERBOSE: bool = false;
+
+fn ledger_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bankex-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn apply(ledger: &mut DiskLedger, example: &'static str) {
+    let rdr = stringreader::StringReader::new(example);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+}
+
+// second batch runs against the state left by the first without replaying it
+#[test]
+fn state_survives_reopen() {
+    let dir = ledger_dir("reopen");
+    {
+        let mut ledger = DiskLedger::open(&dir).unwrap();
+        apply(&mut ledger, "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndeposit, 2, 2, 3.0");
+        ledger.compact().unwrap();
+    }
+    {
+        // no compact here so the next open has to replay the log
+        let mut ledger = DiskLedger::open(&dir).unwrap();
+        apply(&mut ledger, "type, client, tx, amount\ndispute, 1, 1,\nwithdrawal, 2, 3, 1.0\ndeposit, 1, 1, 5.0");
+    }
+    let ledger = DiskLedger::open(&dir).unwrap();
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(0, 0));
+    assert_eq!(ledger.get_funds_held(1).unwrap(), Decimal::new(10, 0));
+    assert_eq!(ledger.get_funds_total(2).unwrap(), Decimal::new(2, 0));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// a line cut short by a crash is dropped instead of failing the open
+#[test]
+fn torn_log_tail_is_ignored() {
+    let dir = ledger_dir("torn");
+    {
+        let mut ledger = DiskLedger::open(&dir).unwrap();
+        apply(&mut ledger, "type, client, tx, amount\ndeposit, 1, 1, 10.0");
+    }
+    let mut log = fs::OpenOptions::new().append(true).open(dir.join("ledger.log")).unwrap();
+    log.write_all(b"{\"kind\":\"account\",\"client\":1,\"avail").unwrap();
+    let ledger = DiskLedger::open(&dir).unwrap();
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(10, 0));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// each accepted transaction is one line that reaches the file before the next row, even if the process never drops the ledger
+#[test]
+fn outcome_is_one_flushed_line() {
+    let dir = ledger_dir("outcome");
+    let mut ledger = DiskLedger::open(&dir).unwrap();
+    apply(&mut ledger, "type, client, tx, amount, destination\ndeposit, 1, 1, 10.0,\ntransfer, 1, 2, 4.0, 2\nwithdrawal, 2, 3, 9.0,");
+    std::mem::forget(ledger);
+    assert_eq!(fs::read_to_string(dir.join("ledger.log")).unwrap().lines().count(), 2);
+    let ledger = DiskLedger::open(&dir).unwrap();
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(6, 0));
+    assert_eq!(ledger.get_funds_available(2).unwrap(), Decimal::new(4, 0));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,92 @@
+use bankex::{Ledger, events::{AccountsProjection, EventSourcedLedger}, policy::LockedAccountPolicy, read, rejected::IgnoreRejections};
+use rust_decimal::Decimal;
+use std::{fs, io::Write, path::PathBuf};
+
+static VERBOSE: bool = false;
+
+fn event_log(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("bankex-events-{}-{}.log", name, std::process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn apply(ledger: &mut EventSourcedLedger, example: &'static str) {
+    let rdr = stringreader::StringReader::new(example);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+}
+
+static EXAMPLE: &str = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 3.0
+withdrawal, 1, 3, 9.0
+dispute, 1, 1,
+resolve, 1, 1,
+withdrawal, 1, 4, 2.0";
+
+// state is rebuilt from the log on open and can be projected at any earlier seq
+#[test]
+fn rebuild_and_replay_to_seq() {
+    let path = event_log("replay");
+    {
+        let mut ledger = EventSourcedLedger::open(&path).unwrap();
+        apply(&mut ledger, EXAMPLE);
+        assert_eq!(ledger.log().last_seq(), 5); // the refused withdrawal is not an event
+    }
+    let mut ledger = EventSourcedLedger::open(&path).unwrap();
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(3, 0));
+    assert_eq!(ledger.get_funds_total(2).unwrap(), Decimal::new(3, 0));
+
+    let at_dispute = ledger.state_at(3).unwrap();
+    assert_eq!(at_dispute.get_funds_available(1).unwrap(), Decimal::new(0, 0));
+    assert_eq!(at_dispute.get_funds_held(1).unwrap(), Decimal::new(5, 0));
+
+    let mut balances = AccountsProjection::default();
+    assert_eq!(ledger.log().replay(Some(1), &mut balances).unwrap(), 1);
+    assert_eq!(balances.by_account.len(), 1);
+    assert_eq!(balances.by_account[&(1, None)].available, Decimal::new(5, 0));
+
+    apply(&mut ledger, "type, client, tx, amount\ndeposit, 2, 5, 1.0");
+    assert_eq!(ledger.log().last_seq(), 6);
+    let _ = fs::remove_file(&path);
+}
+
+// a half written last event is dropped on open and the next event takes its seq
+#[test]
+fn torn_event_is_cut_off() {
+    let path = event_log("torn");
+    {
+        let mut ledger = EventSourcedLedger::open(&path).unwrap();
+        apply(&mut ledger, "type, client, tx, amount\ndeposit, 1, 1, 5.0");
+    }
+    fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"seq\":2,\"line\":").unwrap();
+    {
+        let mut ledger = EventSourcedLedger::open(&path).unwrap();
+        assert_eq!(ledger.log().last_seq(), 1);
+        apply(&mut ledger, "type, client, tx, amount\ndeposit, 1, 2, 1.0");
+    }
+    let ledger = EventSourcedLedger::open(&path).unwrap();
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(6, 0));
+    let _ = fs::remove_file(&path);
+}
+
+// events a locked account took under the run's locked account policy replay whatever the policy on open
+#[test]
+fn locked_policy_events_replay() {
+    let path = event_log("locked");
+    {
+        let mut ledger = EventSourcedLedger::open(&path).unwrap();
+        ledger.set_locked_policy(LockedAccountPolicy::allowing("deposit,dispute").unwrap());
+        apply(&mut ledger, "type, client, tx, amount
+deposit, 1, 1, 5.0
+dispute, 1, 1,
+chargeback, 1, 1,
+deposit, 1, 2, 3.0
+dispute, 1, 2,");
+        assert_eq!(ledger.log().last_seq(), 5);
+    }
+    let ledger = EventSourcedLedger::open(&path).unwrap();
+    assert_eq!(ledger.get_funds_held(1).unwrap(), Decimal::new(3, 0));
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(0, 0));
+    let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,58 @@
+use bankex::{Ledger, error::JournalError, journal::{self, JournalWriter}, ledger::InMemoryLedger, read, rejected::IgnoreRejections};
+use std::{fs::{self, File}, io::{BufReader, BufWriter}, path::PathBuf};
+
+static VERBOSE: bool = false;
+
+fn journal_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("bankex-journal-{}-{}.jsonl", name, std::process::id()))
+}
+
+fn write_journal(path: &PathBuf, example: &'static str) {
+    let rdr = stringreader::StringReader::new(example);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    ledger.set_journal(Box::new(JournalWriter::new(Box::new(BufWriter::new(File::create(path).unwrap())))));
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+    ledger.take_journal().unwrap().finish().unwrap();
+}
+
+fn verify(path: &PathBuf) -> Result<u64, JournalError> {
+    journal::verify_journal(&mut BufReader::new(File::open(path).unwrap()))
+}
+
+static EXAMPLE: &str = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 3.0
+dispute, 1, 1,
+resolve, 1, 1,
+withdrawal, 2, 3, 1.0";
+
+// an untouched journal verifies, an edited or missing line is reported at the first line that no longer links
+#[test]
+fn hash_chain_detects_tampering() {
+    let path = journal_file("chain");
+    write_journal(&path, EXAMPLE);
+    assert_eq!(verify(&path).unwrap(), 5);
+
+    let original = fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = original.lines().collect();
+
+    let mut edited = lines.clone();
+    let changed = lines[1].replacen("\"amount\":\"3\"", "\"amount\":\"30\"", 1);
+    assert_ne!(changed, lines[1]);
+    edited[1] = &changed;
+    fs::write(&path, edited.join("\n")).unwrap();
+    match verify(&path) {
+        Err(JournalError::HashMismatch { line, seq, .. }) => assert_eq!((line, seq), (2, 2)),
+        other => panic!("expected hash mismatch, got {:?}", other),
+    }
+
+    let mut dropped = lines.clone();
+    dropped.remove(2);
+    fs::write(&path, dropped.join("\n")).unwrap();
+    match verify(&path) {
+        Err(JournalError::PrevHashMismatch { line, seq, .. }) => assert_eq!((line, seq), (3, 4)),
+        other => panic!("expected broken link, got {:?}", other),
+    }
+    let _ = fs::remove_file(&path);
+}
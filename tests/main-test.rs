@@ -1,4 +1,14 @@
-use bankex::{Ledger, ledger::InMemoryLedger, read};
+use bankex::{AsOf, DisputeState, Ledger, RunSummary, error::{BankexError, LedgerError}, ledger::InMemoryLedger, read, report::{self, ReportFormat}};
+use bankex::rejected::{CsvRejectionWriter, IgnoreRejections, Rejection, RejectionSink};
+use bankex::journal::{JournalEntry, JournalEvent};
+use bankex::postings::LedgerAccount;
+use bankex::statement::{self, StatementFormat};
+use bankex::policy::LockedAccountPolicy;
+use bankex::precision::{PrecisionTable, Rounding};
+use bankex::audit::AuditPolicy;
+use bankex::sharded::ShardedLedger;
+use std::sync::mpsc;
+use rust_decimal::Decimal;
 
 static VERBOSE: bool = false;
 static ORIGINAL_EXAMPLE: &str = "type, client, tx, amount
@@ -8,15 +18,784 @@ deposit, 1, 3, 2.0
 withdrawal, 1, 4, 1.5
 withdrawal, 2, 5, 3.0";
 
+// dispute is only valid against funds available at the time it appears in the file
+static INTERLEAVED_EXAMPLE: &str = "type, client, tx, amount
+deposit, 1, 1, 5.0
+dispute, 1, 1,
+withdrawal, 1, 2, 3.0
+resolve, 1, 1,
+deposit, 1, 3, 1.0";
+
+fn run_example(example: &'static str) -> InMemoryLedger {
+    let rdr = stringreader::StringReader::new(example);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+    ledger
+}
+
+fn first_transaction(example: &'static str) -> bankex::Transaction {
+    let rdr = stringreader::StringReader::new(example);
+    read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap().next().unwrap().unwrap()
+}
+
 // simply run original example without errors
 #[test]
 fn read_original_example() {
+    let ledger = run_example(ORIGINAL_EXAMPLE);
+    ledger.run_report(&mut std::io::sink()).unwrap();
+}
+
+// rows sorted by client id with four decimal places, no leading blank line
+#[test]
+fn report_is_reproducible() {
+    let ledger = run_example(ORIGINAL_EXAMPLE);
+    let mut out: Vec<u8> = Vec::new();
+    ledger.run_report(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,2.0000,0.0000,2.0000,false
+");
+}
+
+// the withdrawal must fail because the disputed funds were held when it was processed
+#[test]
+fn read_interleaved_in_file_order() {
+    let ledger = run_example(INTERLEAVED_EXAMPLE);
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(60, 1));
+    assert_eq!(ledger.get_funds_total(1).unwrap(), Decimal::new(60, 1));
+}
+
+// resolve before dispute and a second dispute of the same tx must not move funds
+static DISPUTE_LIFECYCLE_EXAMPLE: &str = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 2.0
+resolve, 1, 1,
+chargeback, 1, 2,
+dispute, 1, 1,
+dispute, 1, 1,
+chargeback, 1, 1,
+resolve, 1, 1,";
+
+#[test]
+fn dispute_lifecycle_enforced() {
+    let ledger = run_example(DISPUTE_LIFECYCLE_EXAMPLE);
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(20, 1));
+    assert_eq!(ledger.get_funds_held(1).unwrap(), Decimal::new(0, 0));
+    assert_eq!(ledger.get_funds_total(1).unwrap(), Decimal::new(20, 1));
+    assert!(ledger.by_account[&(1, None)].locked);
+    assert_eq!(ledger.by_transaction_id[&1].state, DisputeState::ChargedBack);
+    assert_eq!(ledger.by_transaction_id[&2].state, DisputeState::Processed);
+}
+
+#[test]
+fn illegal_transition_is_distinct_error() {
+    let mut ledger = run_example("type, client, tx, amount\ndeposit, 1, 1, 5.0");
+    let resolve = first_transaction("type, client, tx, amount\nresolve, 1, 1,");
+    let err = ledger.process_resolve(VERBOSE, &resolve).unwrap_err();
+    assert_eq!(err, LedgerError::IllegalTransition { tx: 1, from: DisputeState::Processed, to: DisputeState::Resolved });
+}
+
+#[test]
+fn rejections_carry_ids_and_amounts() {
+    let mut ledger = run_example("type, client, tx, amount\ndeposit, 1, 1, 5.0");
+    let withdrawal = first_transaction("type, client, tx, amount\nwithdrawal, 1, 2, 7.5");
+    assert_eq!(ledger.process_transaction(VERBOSE, &withdrawal).unwrap_err(),
+        LedgerError::InsufficientFunds { client: 1, tx: 2, amount: Decimal::new(75, 1), available: Decimal::new(50, 1) });
+    let deposit = first_transaction("type, client, tx, amount\ndeposit, 2, 1, 1.0");
+    assert_eq!(ledger.process_transaction(VERBOSE, &deposit).unwrap_err(),
+        LedgerError::DuplicateTransaction { client: 2, tx: 1 });
+    let dispute = first_transaction("type, client, tx, amount\ndispute, 2, 1,");
+    assert_eq!(ledger.process_transaction(VERBOSE, &dispute).unwrap_err(),
+        LedgerError::ClientMismatch { client: 2, tx: 1, owner: Some(1) });
+}
+
+static REJECTED_EXAMPLE: &str = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, x, 2, 1.0
+withdrawal, 1, 3, 4.0
+dispute, 2, 1,
+deposit, 1, 4, 2.0";
+
+// every refused row is reported with its line and reason while the good rows still apply
+#[test]
+fn rejected_rows_are_collected() {
+    let rdr = stringreader::StringReader::new(REJECTED_EXAMPLE);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    let mut rejections: Vec<Rejection> = Vec::new();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(30, 1));
+    let summary: Vec<(Option<u64>, &str, &str)> = rejections.iter().map(|r| (r.line, r.reason, r.client.as_str())).collect();
+    assert_eq!(summary, vec![
+        (Some(3), "malformed_row", "x"),
+        (Some(4), "insufficient_funds", "1"),
+        (Some(5), "client_mismatch", "2"),
+    ]);
+    assert_eq!(rejections[1].tx, "3");
+}
+
+// restored ledger keeps dispute status so a later resolve still applies
+#[test]
+fn snapshot_round_trip() {
+    let ledger = run_example("type, client, tx, amount\ndeposit, 1, 1, 5.0\ndispute, 1, 1,");
+    let mut saved: Vec<u8> = Vec::new();
+    ledger.save_snapshot(&mut saved).unwrap();
+    let mut restored = InMemoryLedger::default();
+    restored.load_snapshot(&mut saved.as_slice()).unwrap();
+    assert_eq!(restored.by_transaction_id[&1].state, DisputeState::Disputed);
+    let resolve = first_transaction("type, client, tx, amount\nresolve, 1, 1,");
+    restored.process_transaction(VERBOSE, &resolve).unwrap();
+    assert_eq!(restored.get_funds_available(1).unwrap(), Decimal::new(50, 1));
+}
+
+#[test]
+fn snapshot_version_checked() {
+    let mut ledger = InMemoryLedger::default();
+    let future = br#"{"version":99,"accounts":[],"transactions":[]}"#;
+    assert_eq!(ledger.load_snapshot(&mut &future[..]).unwrap_err(),
+        LedgerError::UnsupportedSnapshot { version: 99, expected: bankex::snapshot::SNAPSHOT_VERSION });
+}
+
+static ORIGINAL_EXAMPLE_JSONL: &str = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}
+{"type": "deposit", "client": 2, "tx": 2, "amount": "2.0"}
+{"type": "deposit", "client": 1, "tx": 3, "amount": 2.0}
+
+{"type": "withdrawal", "client": 1, "tx": 4, "amount": "1.5"}
+{"type": "withdrawal", "client": 2, "tx": 5, "amount": "3.0"}
+{"type": "deposit", "client": 2, "tx": 6}"#;
+
+// json lines input produces the same ledger as the csv example and rejects with the json line number
+#[test]
+fn jsonl_matches_csv() {
+    let rdr = stringreader::StringReader::new(ORIGINAL_EXAMPLE_JSONL);
+    let mut transactions = read::jsonl_reader_from(VERBOSE, Box::new(rdr));
+    let mut ledger = InMemoryLedger::default();
+    let mut rejections: Vec<Rejection> = Vec::new();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    let mut jsonl_report: Vec<u8> = Vec::new();
+    ledger.run_report(&mut jsonl_report).unwrap();
+    let mut csv_report: Vec<u8> = Vec::new();
+    run_example(ORIGINAL_EXAMPLE).run_report(&mut csv_report).unwrap();
+    assert_eq!(jsonl_report, csv_report);
+    let summary: Vec<(Option<u64>, &str)> = rejections.iter().map(|r| (r.line, r.reason)).collect();
+    assert_eq!(summary, vec![(Some(6), "insufficient_funds"), (Some(7), "missing_amount")]);
+}
+
+// json lines may name an asset or destination on any line, and a row that can not be read still reports them
+#[test]
+fn jsonl_optional_columns() {
+    let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "5.0"}
+{"type": "transfer", "client": 1, "tx": 2, "amount": "1.0", "destination": 2}
+{"type": "deposit", "client": 1, "tx": 3, "amount": "oops", "currency": "BTC", "destination": 2}"#;
+    let path = std::env::temp_dir().join(format!("bankex-columns-{}.jsonl", std::process::id()));
+    std::fs::write(&path, input).unwrap();
+    let mut columns = read::input_columns(path.to_str().unwrap(), None).unwrap();
+    let _ = std::fs::remove_file(&path);
+    columns.sort();
+    assert_eq!(columns, ["amount", "client", "currency", "destination", "tx", "type"]);
+
+    let rdr = stringreader::StringReader::new(input);
+    let mut transactions = read::jsonl_reader_from(VERBOSE, Box::new(rdr));
+    let mut rejections: Vec<Rejection> = Vec::new();
+    InMemoryLedger::default().read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    assert_eq!(rejections.len(), 1);
+    assert_eq!((rejections[0].reason, rejections[0].amount.as_str()), ("malformed_row", "oops"));
+    assert_eq!((rejections[0].asset.as_deref(), rejections[0].destination.as_deref()), (Some("BTC"), Some("2")));
+}
+
+#[test]
+fn input_format_from_extension() {
+    assert_eq!(read::InputFormat::from_path("partner/2021-04-01.jsonl"), read::InputFormat::Jsonl);
+    assert_eq!(read::InputFormat::from_path("partner/2021-04-01.NDJSON"), read::InputFormat::Jsonl);
+    assert_eq!(read::InputFormat::from_path("transactions.csv"), read::InputFormat::Csv);
+    assert_eq!(read::InputFormat::from_path("transactions"), read::InputFormat::Csv);
+}
+
+// json report keeps four decimal places as strings and carries the run counts
+#[test]
+fn json_report_includes_summary() {
     let rdr = stringreader::StringReader::new(ORIGINAL_EXAMPLE);
-    let (withdraw_deposits,
-         disputes,
-          resolves,
-           chargebacks) = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    let summary = ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+    assert_eq!(summary, RunSummary { processed: 5, accepted: 4, rejected: 1 });
+
+    let mut json: Vec<u8> = Vec::new();
+    report::write_report(&mut json, ReportFormat::Json, &mut ledger.accounts(), &summary, &PrecisionTable::default()).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json["accounts"][0]["available"], "1.5000");
+    assert_eq!(json["accounts"][1]["total"], "2.0000");
+    assert_eq!(json["summary"]["rejected"], 1);
+
+    let mut jsonl: Vec<u8> = Vec::new();
+    report::write_report(&mut jsonl, ReportFormat::Jsonl, &mut ledger.accounts(), &summary, &PrecisionTable::default()).unwrap();
+    let lines: Vec<&str> = std::str::from_utf8(&jsonl).unwrap().lines().collect();
+    assert_eq!(lines, vec![
+        r#"{"client":1,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}"#,
+        r#"{"client":2,"available":"2.0000","held":"0.0000","total":"2.0000","locked":false}"#,
+        r#"{"summary":{"processed":5,"accepted":4,"rejected":1}}"#,
+    ]);
+}
+
+// every balance change is journaled in order with the balances either side of it
+#[test]
+fn journal_records_balance_changes() {
+    let rdr = stringreader::StringReader::new("type, client, tx, amount
+deposit, 1, 1, 5.0
+withdrawal, 1, 2, 9.0
+dispute, 1, 1,
+chargeback, 1, 1,");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    let (journal, entries) = mpsc::channel();
+    ledger.set_journal(Box::new(journal));
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+    drop(ledger.take_journal());
+    let entries: Vec<JournalEntry> = entries.iter().collect();
+    let events: Vec<(u64, Option<u64>, JournalEvent)> = entries.iter().map(|e| (e.seq, e.line, e.event)).collect();
+    assert_eq!(events, vec![
+        (1, Some(2), JournalEvent::Credit),
+        (2, Some(4), JournalEvent::Hold),
+        (3, Some(5), JournalEvent::Reversal),
+        (4, Some(5), JournalEvent::Lock),
+    ]);
+    for pair in entries.windows(2) {
+        assert_eq!(pair[0].after, pair[1].before);
+    }
+    assert_eq!(entries[1].after.held, Decimal::new(5, 0));
+    assert_eq!(entries[2].after.held, Decimal::new(0, 0));
+    assert!(!entries[2].after.locked && entries[3].after.locked);
+}
+
+// every movement has a counterparty, so the books add up to zero and back the client balances
+#[test]
+fn trial_balance_adds_up() {
+    let ledger = run_example("type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 3.0
+withdrawal, 2, 3, 1.0
+dispute, 1, 1,
+chargeback, 1, 1,
+withdrawal, 2, 4, 9.0");
+    let trial_balance = ledger.check_trial_balance().unwrap();
+    assert!(trial_balance.total().is_zero());
+    assert_eq!(trial_balance.balance(LedgerAccount::PartnerSettlement), Decimal::new(-7, 0));
+    assert_eq!(trial_balance.balance(LedgerAccount::ChargebackLosses), Decimal::new(5, 0));
+    assert_eq!(trial_balance.balance(LedgerAccount::ClientAvailable(2)), Decimal::new(2, 0));
+
+    let mut snapshot: Vec<u8> = Vec::new();
+    ledger.save_snapshot(&mut snapshot).unwrap();
+    let mut restored = InMemoryLedger::default();
+    restored.load_snapshot(&mut snapshot.as_slice()).unwrap();
+    let restored = restored.check_trial_balance().unwrap();
+    for account in [LedgerAccount::PartnerSettlement, LedgerAccount::ChargebackLosses, LedgerAccount::ClientAvailable(1), LedgerAccount::ClientHeld(1)] {
+        assert_eq!(restored.balance(account), trial_balance.balance(account));
+    }
+}
+
+// balances can be asked for at an input line or after a number of accepted transactions
+#[test]
+fn account_as_of_line_and_seq() {
+    let as_of = |client: u16, as_of: AsOf| {
+        let rdr = stringreader::StringReader::new(INTERLEAVED_EXAMPLE);
+        let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+        InMemoryLedger::default().account_as_of(VERBOSE, client, as_of, &mut transactions)
+    };
+    let cas = as_of(1, AsOf::Line(3)).unwrap();
+    assert_eq!((cas.available, cas.held, cas.total), (Decimal::new(0, 0), Decimal::new(5, 0), Decimal::new(5, 0)));
+    // the withdrawal on line 4 is refused so the third accepted transaction is the resolve on line 5
+    let cas = as_of(1, AsOf::Seq(3)).unwrap();
+    assert_eq!((cas.available, cas.held), (Decimal::new(5, 0), Decimal::new(0, 0)));
+    assert!(matches!(as_of(2, AsOf::Line(6)), Err(BankexError::Ledger(LedgerError::UnknownAccount { client: 2 }))));
+}
+
+// a statement lists only the client's rows, accepted or not, with the account after each
+#[test]
+fn statement_for_one_client() {
+    let rdr = stringreader::StringReader::new("type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 1.0
+withdrawal, 1, 3, 9.0
+dispute, 1, 1,
+resolve, 1, 1,");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    let mut out: Vec<u8> = Vec::new();
+    statement::write_statement(VERBOSE, 1, &mut ledger, &mut transactions, StatementFormat::Csv, &PrecisionTable::default(), &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "line,type,tx,amount,status,reason,available,held,total,locked
+2,deposit,1,5,accepted,,5.0000,0.0000,5.0000,false
+4,withdrawal,3,9,rejected,insufficient_funds,5.0000,0.0000,5.0000,false
+5,dispute,1,,accepted,,0.0000,5.0000,5.0000,false
+6,resolve,1,,accepted,,5.0000,0.0000,5.0000,false
+");
+    // the other client's rows were still processed
+    assert_eq!(ledger.get_funds_available(2).unwrap(), Decimal::new(1, 0));
+}
+
+// the statement and the trial balance write each asset's amounts with the places of that asset
+#[test]
+fn amounts_use_asset_places() {
+    let input = "type, client, tx, amount, asset
+deposit, 1, 1, 5, JPY
+deposit, 1, 2, 0.12345678, BTC";
+    let precision = PrecisionTable::from_spec("BTC=8,JPY=0").unwrap();
+    let rdr = stringreader::StringReader::new(input);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap().with_precision(precision.clone());
+    let mut out: Vec<u8> = Vec::new();
+    statement::write_statement(VERBOSE, 1, &mut InMemoryLedger::default(), &mut transactions, StatementFormat::Csv, &precision, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "line,type,tx,amount,status,reason,available,held,total,locked,asset
+2,deposit,1,5,accepted,,5,0,5,false,JPY
+3,deposit,2,0.12345678,accepted,,0.12345678,0.00000000,0.12345678,false,BTC
+");
+
+    let rdr = stringreader::StringReader::new(input);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap().with_precision(precision.clone());
+    let mut ledger = InMemoryLedger::default();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+    let mut out: Vec<u8> = Vec::new();
+    ledger.check_trial_balance().unwrap().write_csv(&mut out, &precision).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "account,balance
+BTC:partner_settlement,-0.12345678
+BTC:client:1:available,0.12345678
+BTC:total,0.00000000
+JPY:partner_settlement,-5
+JPY:client:1:available,5
+JPY:total,0
+");
+}
+
+// in audit mode a broken invariant stops the run at the transaction that exposed it
+#[test]
+fn audit_stops_at_first_violation() {
+    let mut ledger = InMemoryLedger::default();
+    ledger.set_audit(AuditPolicy::default());
+    let rdr = stringreader::StringReader::new("type, client, tx, amount\ndeposit, 1, 1, 5.0\ndispute, 1, 1,\ndeposit, 2, 2, 1.0");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let summary = ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+    assert_eq!(summary.rejected, 0);
+
+    // held no longer matches the open dispute on tx 1
+    ledger.by_account.get_mut(&(1, None)).unwrap().held += Decimal::new(1, 0);
+    let rdr = stringreader::StringReader::new("type, client, tx, amount\ndeposit, 2, 3, 1.0\ndeposit, 1, 4, 1.0\ndeposit, 1, 5, 1.0");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    match ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections) {
+        Err(BankexError::Ledger(LedgerError::InvariantViolated { client, tx, line, invariant, .. })) => {
+            assert_eq!((client, tx, line, invariant), (1, 4, Some(3), "held_matches_disputes"));
+        },
+        other => panic!("expected invariant violation, got {:?}", other),
+    }
+    assert_eq!(ledger.get_funds_available(2).unwrap(), Decimal::new(2, 0));
+    assert!(transactions.next().is_some()); // the row after the violation was never read
+}
+
+// the destination a transfer credits is audited too, also when it is locked and the policy lets transfers in
+#[test]
+fn audit_checks_transfer_destination() {
+    let mut ledger = InMemoryLedger::default();
+    ledger.set_audit(AuditPolicy::default());
+    let rdr = stringreader::StringReader::new("type, client, tx, amount, destination
+deposit, 1, 1, 10.0,
+deposit, 2, 2, 5.0,
+dispute, 2, 2,
+chargeback, 2, 2,
+transfer, 1, 3, 1.0, 2");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut rejections: Vec<Rejection> = Vec::new();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    assert_eq!(rejections.iter().map(|r| (r.line, r.reason)).collect::<Vec<_>>(), vec![(Some(6), "account_locked")]);
+
+    ledger.set_locked_policy(LockedAccountPolicy::allowing("transfer").unwrap());
+    let rdr = stringreader::StringReader::new("type, client, tx, amount, destination\ntransfer, 1, 4, 1.0, 2");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+    assert_eq!(ledger.get_funds_available(2).unwrap(), Decimal::new(1, 0));
+
+    // held of the locked destination no longer matches its open disputes
+    ledger.by_account.get_mut(&(2, None)).unwrap().held += Decimal::new(1, 0);
+    ledger.by_account.get_mut(&(2, None)).unwrap().total += Decimal::new(1, 0);
+    let rdr = stringreader::StringReader::new("type, client, tx, amount, destination\ntransfer, 1, 5, 1.0, 2");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    match ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections) {
+        Err(BankexError::Ledger(LedgerError::InvariantViolated { client, tx, invariant, .. })) => {
+            assert_eq!((client, tx, invariant), (2, 5, "held_matches_disputes"));
+        },
+        other => panic!("expected invariant violation, got {:?}", other),
+    }
+}
+
+// a locked account only accepts the transaction types its policy allows
+#[test]
+fn locked_account_policy() {
+    let run = |policy: LockedAccountPolicy| {
+        let rdr = stringreader::StringReader::new("type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 3.0
+dispute, 1, 1,
+chargeback, 1, 1,
+dispute, 1, 2,
+withdrawal, 1, 3, 1.0");
+        let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+        let mut ledger = InMemoryLedger::default();
+        ledger.set_locked_policy(policy);
+        let mut rejections: Vec<Rejection> = Vec::new();
+        ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+        let rejected: Vec<(Option<u64>, &str)> = rejections.iter().map(|r| (r.line, r.reason)).collect();
+        (ledger.get_funds_held(1).unwrap(), rejected)
+    };
+    assert_eq!(run(LockedAccountPolicy::default()), (Decimal::new(0, 0), vec![(Some(6), "account_locked"), (Some(7), "account_locked")]));
+    assert_eq!(run(LockedAccountPolicy::allowing("dispute,resolve").unwrap()), (Decimal::new(3, 0), vec![(Some(7), "account_locked")]));
+    assert!(LockedAccountPolicy::allowing("dispute,refund").is_err());
+}
+
+#[test]
+fn admin_unlock_and_adjustments() {
+    let rdr = stringreader::StringReader::new("type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 3.0
+dispute, 1, 1,
+chargeback, 1, 1,
+unlock, 1, 3,");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    ledger.set_audit(AuditPolicy::default());
+    let mut rejections: Vec<Rejection> = Vec::new();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    assert_eq!(rejections.iter().map(|r| (r.line, r.reason)).collect::<Vec<_>>(), vec![(Some(6), "admin_only")]);
+    let rdr = stringreader::StringReader::new("type, client, tx, amount, reason
+adjust_credit, 1, 10, 2.0,
+unlock, 1, 11, , chargeback reversed by partner
+adjust_credit, 1, 12, 2.0, goodwill
+adjust_debit, 1, 13, 0.5, fee correction
+deposit, 1, 14, 1.0, not an operator type
+dispute, 1, 12,
+adjust_credit, 9, 15, 1.0, no such client
+adjust_debit, 1, 16, -2.0, negative debit
+adjust_credit, 1, 17, -2.0, negative credit
+adjust_credit, 1, 18, 0, zero credit");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut rejections: Vec<Rejection> = Vec::new();
+    let summary = ledger.read_admin_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    assert_eq!(summary, RunSummary { processed: 10, accepted: 3, rejected: 7 });
+    let rejected: Vec<(Option<u64>, &str)> = rejections.iter().map(|r| (r.line, r.reason)).collect();
+    assert_eq!(rejected, vec![(Some(2), "missing_reason"), (Some(6), "not_admin"), (Some(7), "not_admin"), (Some(8), "unknown_account"),
+        (Some(9), "invalid_amount"), (Some(10), "invalid_amount"), (Some(11), "invalid_amount")]);
+    let cas = ledger.accounts().find(|cas| cas.client == 1).unwrap();
+    assert_eq!((cas.available, cas.locked), (Decimal::new(45, 1), false));
+    // unlocked accounts take partner transactions again but adjustments can not be disputed
+    let rdr = stringreader::StringReader::new("type, client, tx, amount
+withdrawal, 1, 20, 1.0
+dispute, 1, 12,");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut rejections: Vec<Rejection> = Vec::new();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    assert_eq!(rejections.iter().map(|r| r.reason).collect::<Vec<_>>(), vec!["not_disputable"]);
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(35, 1));
+    let trial_balance = ledger.check_trial_balance().unwrap();
+    assert_eq!(trial_balance.balance(LedgerAccount::OperatorAdjustments), Decimal::new(-15, 1));
+}
+
+// every asset of a client is a separate account, disputes follow the asset of the transaction they refer to
+#[test]
+fn multi_asset_accounts() {
+    let rdr = stringreader::StringReader::new("type, client, tx, amount, asset
+deposit, 1, 1, 5.0,
+deposit, 1, 2, 2.0, BTC
+deposit, 1, 3, 100.0, EUR
+withdrawal, 1, 4, 3.0, BTC
+dispute, 1, 2,
+chargeback, 1, 2,
+deposit, 1, 5, 1.0, BTC
+deposit, 1, 6, 1.0, EUR
+dispute, 1, 3, , BTC");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    ledger.set_audit(AuditPolicy::default());
+    let mut rejections: Vec<Rejection> = Vec::new();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    let rejected: Vec<(Option<u64>, &str)> = rejections.iter().map(|r| (r.line, r.reason)).collect();
+    assert_eq!(rejected, vec![(Some(5), "insufficient_funds"), (Some(8), "account_locked"), (Some(10), "asset_mismatch")]);
+    assert!(rejections.iter().all(|r| r.asset.as_deref() == Some("BTC")));
+    let mut out = Vec::new();
+    report::write_accounts(&mut out, &mut ledger.accounts(), &PrecisionTable::default()).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "client,asset,available,held,total,locked
+1,,5.0000,0.0000,5.0000,false
+1,BTC,0.0000,0.0000,0.0000,true
+1,EUR,101.0000,0.0000,101.0000,false
+");
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(5, 0));
+    let trial_balance = ledger.check_trial_balance().unwrap();
+    assert_eq!(trial_balance.balance_in(Some("BTC"), LedgerAccount::ChargebackLosses), Decimal::new(2, 0));
+    assert_eq!(trial_balance.balance(LedgerAccount::ChargebackLosses), Decimal::new(0, 0));
+}
+
+// the asset of a rejected row or statement line is only written when there is one, and in csv every row has the column
+#[test]
+fn asset_of_rejections_and_statements() {
+    let input = "type, client, tx, amount, asset
+deposit, 1, 1, 5.0,
+withdrawal, 1, 2, 9.0,
+withdrawal, 1, 3, 1.0, BTC";
+    let path = std::env::temp_dir().join(format!("bankex-rejected-{}.csv", std::process::id()));
+    let rdr = stringreader::StringReader::new(input);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut rejections = CsvRejectionWriter::new(Box::new(std::fs::File::create(&path).unwrap())).with_asset_column(true);
+    InMemoryLedger::default().read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    rejections.finish().unwrap();
+    let written = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let rows: Vec<&str> = written.lines().collect();
+    assert_eq!(rows[0], "line,type,client,tx,amount,reason,detail,asset");
+    assert!(rows[1].starts_with("3,withdrawal,") && rows[1].ends_with(","));
+    assert!(rows[2].starts_with("4,withdrawal,") && rows[2].ends_with(",BTC"));
+
+    let mut json = Vec::new();
+    let rdr = stringreader::StringReader::new(input);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut rejections: Vec<Rejection> = Vec::new();
+    InMemoryLedger::default().read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    serde_json::to_writer(&mut json, &rejections[0]).unwrap();
+    assert!(!String::from_utf8(json).unwrap().contains("asset"));
+
+    let rdr = stringreader::StringReader::new(input);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut out: Vec<u8> = Vec::new();
+    statement::write_statement(VERBOSE, 1, &mut InMemoryLedger::default(), &mut transactions, StatementFormat::Csv, &PrecisionTable::default(), &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "line,type,tx,amount,status,reason,available,held,total,locked,asset
+2,deposit,1,5,accepted,,5.0000,0.0000,5.0000,false,
+3,withdrawal,2,9,rejected,insufficient_funds,5.0000,0.0000,5.0000,false,
+4,withdrawal,3,1,rejected,insufficient_funds,0.0000,0.0000,0.0000,false,BTC
+");
+}
+
+// amounts are rounded to the places of their asset, or rejected in strict mode, and reported with those places
+#[test]
+fn per_asset_precision() {
+    let input = "type, client, tx, amount, asset
+deposit, 1, 1, 1.123456789, BTC
+deposit, 1, 2, 150.5, JPY
+deposit, 1, 3, 2.00000, JPY
+deposit, 1, 4, 0.12345,";
+    let run = |precision: PrecisionTable| {
+        let rdr = stringreader::StringReader::new(input);
+        let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap().with_precision(precision.clone());
         let mut ledger = InMemoryLedger::default();
-        ledger.read_transactions(false, withdraw_deposits, disputes, resolves, chargebacks);
-        ledger.run_report();
+        let mut rejections: Vec<Rejection> = Vec::new();
+        let summary = ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+        let mut out = Vec::new();
+        report::write_report(&mut out, ReportFormat::Csv, &mut ledger.accounts(), &summary, &precision).unwrap();
+        (String::from_utf8(out).unwrap(), rejections.iter().map(|r| (r.line, r.reason)).collect::<Vec<_>>())
+    };
+    let precision = PrecisionTable::from_spec("BTC=8, JPY=0").unwrap();
+    assert_eq!(run(precision.clone()), ("client,asset,available,held,total,locked
+1,,0.1234,0.0000,0.1234,false
+1,BTC,1.12345679,0.00000000,1.12345679,false
+1,JPY,152,0,152,false
+".to_string(), vec![(Some(2), "rounded"), (Some(3), "rounded"), (Some(5), "rounded")]));
+    assert_eq!(run(precision.with_rounding(Rounding::Reject)), ("client,asset,available,held,total,locked
+1,JPY,2,0,2,false
+".to_string(), vec![(Some(2), "too_precise"), (Some(3), "too_precise"), (Some(5), "too_precise")]));
+    assert!(PrecisionTable::from_spec("BTC=29").is_err());
+    assert!(PrecisionTable::from_spec("BTC").is_err());
+}
+
+// every strategy rounds the midpoint its own way and each rounded amount is listed with its adjustment
+#[test]
+fn rounding_strategies() {
+    let run = |rounding: Rounding| {
+        let rdr = stringreader::StringReader::new("type, client, tx, amount
+deposit, 1, 1, 1.00005
+deposit, 2, 2, 1.00015
+deposit, 3, 3, 1.00007
+deposit, 4, 4, 2.50000");
+        let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap().with_precision(PrecisionTable::default().with_rounding(rounding));
+        let mut ledger = InMemoryLedger::default();
+        ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+        (1..=4).map(|client| ledger.get_funds_available(client).ok()).collect::<Vec<_>>()
+    };
+    let amounts = |v: &[i64]| v.iter().map(|&n| Some(Decimal::new(n, 4))).collect::<Vec<_>>();
+    assert_eq!(run(Rounding::HalfEven), amounts(&[10000, 10002, 10001, 25000]));
+    assert_eq!(run(Rounding::HalfUp), amounts(&[10001, 10002, 10001, 25000]));
+    assert_eq!(run(Rounding::HalfDown), amounts(&[10000, 10001, 10001, 25000]));
+    assert_eq!(run(Rounding::Truncate), amounts(&[10000, 10001, 10000, 25000]));
+    assert_eq!(run(Rounding::Reject), vec![None, None, None, Some(Decimal::new(25000, 4))]);
+
+    let rdr = stringreader::StringReader::new("type, client, tx, amount
+deposit, 1, 1, 1.00005
+withdrawal, 1, 2, 5.00005");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap().with_precision(PrecisionTable::default().with_rounding(Rounding::HalfUp));
+    let mut ledger = InMemoryLedger::default();
+    let mut rejections: Vec<Rejection> = Vec::new();
+    let summary = ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    assert_eq!(summary, RunSummary { processed: 2, accepted: 1, rejected: 1 });
+    // the refused withdrawal is only listed as rejected, its rounding never reached the books
+    let listed: Vec<(Option<u64>, &str, &str, &str)> = rejections.iter().map(|r| (r.line, r.reason, r.amount.as_str(), r.detail.as_str())).collect();
+    assert_eq!(listed[0], (Some(2), "rounded", "1.00005", "amount:[1.00005] rounded to:[1.0001] adjustment:[0.00005]"));
+    assert_eq!(listed[1].1, "insufficient_funds");
+    assert_eq!(listed.len(), 2);
+}
+
+// a transfer debits the source and credits the destination in one step, and a chargeback of it returns the funds
+#[test]
+fn transfers_between_clients() {
+    let input = "type, client, tx, amount, destination
+deposit, 1, 1, 10.0,
+deposit, 3, 2, 1.0,
+transfer, 1, 3, 4.0, 2
+transfer, 1, 4, 20.0, 2
+transfer, 1, 5, 1.0, 1
+transfer, 1, 6, 1.0,
+dispute, 1, 3,
+chargeback, 1, 3,
+transfer, 1, 7, 1.0, 2
+transfer, 3, 8, 1.0, 1
+transfer, 1, 9, -5.0, 3
+transfer, 1, 10, 0, 3";
+    let rdr = stringreader::StringReader::new(input);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    ledger.set_audit(AuditPolicy::default());
+    let mut rejections: Vec<Rejection> = Vec::new();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    let rejected: Vec<(Option<u64>, &str)> = rejections.iter().map(|r| (r.line, r.reason)).collect();
+    assert_eq!(rejected, vec![(Some(5), "insufficient_funds"), (Some(6), "self_transfer"), (Some(7), "missing_destination"), (Some(10), "account_locked"),
+        (Some(12), "invalid_amount"), (Some(13), "invalid_amount")]);
+    assert_eq!(rejections[1].destination.as_deref(), Some("1"));
+    assert_eq!(rejections[2].destination, None);
+    let mut out = Vec::new();
+    report::write_accounts(&mut out, &mut ledger.accounts(), &PrecisionTable::default()).unwrap();
+    assert_eq!(String::from_utf8(out.clone()).unwrap(), "client,available,held,total,locked
+1,11.0000,0.0000,11.0000,false
+2,0.0000,0.0000,0.0000,true
+3,0.0000,0.0000,0.0000,false
+");
+    let trial_balance = ledger.check_trial_balance().unwrap();
+    assert_eq!(trial_balance.balance(LedgerAccount::ChargebackLosses), Decimal::new(0, 0));
+
+    // the transfer, dispute and chargeback between clients 1 and 2 reach across shards
+    for workers in 2..=3 {
+        let rdr = stringreader::StringReader::new(input);
+        let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+        let mut sharded = ShardedLedger::new(workers);
+        let mut sharded_rejections: Vec<Rejection> = Vec::new();
+        sharded.read_transactions(VERBOSE, &mut transactions, &mut sharded_rejections).unwrap();
+        sharded_rejections.sort_by_key(|r| r.line);
+        assert_eq!(sharded_rejections, rejections);
+        let mut sharded_out = Vec::new();
+        report::write_accounts(&mut sharded_out, &mut sharded.accounts(), &PrecisionTable::default()).unwrap();
+        assert_eq!(sharded_out, out);
+        sharded.check_trial_balance().unwrap();
+    }
+}
+
+// a ledger restored with a disputed transfer counts the held funds on the destination when audit is turned on
+#[test]
+fn audit_restored_transfer_dispute() {
+    let rdr = stringreader::StringReader::new("type, client, tx, amount, destination
+deposit, 1, 1, 10.0,
+transfer, 1, 2, 4.0, 2
+dispute, 1, 2,");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+    let mut snapshot: Vec<u8> = Vec::new();
+    ledger.save_snapshot(&mut snapshot).unwrap();
+
+    let mut restored = InMemoryLedger::default();
+    restored.load_snapshot(&mut snapshot.as_slice()).unwrap();
+    restored.set_audit(AuditPolicy::default());
+    let rdr = stringreader::StringReader::new("type, client, tx, amount
+deposit, 2, 3, 1.0
+resolve, 1, 2,");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut rejections: Vec<Rejection> = Vec::new();
+    restored.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    assert!(rejections.is_empty(), "{:?}", rejections);
+    assert_eq!(restored.get_funds_held(2).unwrap(), Decimal::new(0, 0));
+    assert_eq!(restored.get_funds_available(2).unwrap(), Decimal::new(5, 0));
+}
+
+// authorizations reserve funds apart from dispute holds until they are captured, in part or in full, voided or expire
+#[test]
+fn authorization_holds() {
+    let rdr = stringreader::StringReader::new("type, client, tx, amount, timestamp, expires_at
+deposit, 1, 1, 10.0, ,
+authorize, 1, 2, 4.0, 100, 200
+capture, 1, 2, 1.5, 110,
+capture, 1, 2, , 120,
+authorize, 1, 3, 20.0, ,
+authorize, 1, 4, 3.0, 130, 200
+void, 1, 4, , ,
+authorize, 1, 5, 2.0, 140, 200
+capture, 1, 5, 3.0, 150,
+dispute, 1, 5, , ,
+capture, 1, 1, , ,
+deposit, 1, 6, 1.0, 250,
+capture, 1, 5, , 260,
+authorize, 1, 7, 1.0, 300, 400
+capture, 1, 7, , 400,
+authorize, 1, 8, -1.0, ,
+authorize, 1, 9, 0, ,
+capture, 1, 7, -3.0, ,
+capture, 1, 7, , ,");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    ledger.set_audit(AuditPolicy::default());
+    let mut rejections: Vec<Rejection> = Vec::new();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    let rejected: Vec<(Option<u64>, &str)> = rejections.iter().map(|r| (r.line, r.reason)).collect();
+    assert_eq!(rejected, vec![(Some(5), "authorization_closed"), (Some(6), "insufficient_funds"), (Some(10), "capture_exceeds_authorization"),
+        (Some(11), "not_disputable"), (Some(12), "not_authorization"), (Some(14), "authorization_closed"), (Some(16), "authorization_expired"),
+        (Some(17), "invalid_amount"), (Some(18), "invalid_amount"), (Some(19), "invalid_amount"), (Some(20), "authorization_closed")]);
+    let mut out = Vec::new();
+    report::write_accounts(&mut out, &mut ledger.accounts(), &PrecisionTable::default()).unwrap();
+    // the refused capture at the expiry released the authorization, so nothing is reserved any more
+    assert_eq!(String::from_utf8(out).unwrap(), "client,available,held,total,locked
+1,9.5000,0.0000,9.5000,false
+");
+    let trial_balance = ledger.check_trial_balance().unwrap();
+    assert_eq!(trial_balance.balance(LedgerAccount::ClientAuthorized(1)), Decimal::new(0, 0));
+
+    // without timestamps an authorization runs out after the configured number of later transactions on its account
+    let rdr = stringreader::StringReader::new("type, client, tx, amount
+deposit, 1, 1, 5.0
+authorize, 1, 2, 2.0
+deposit, 2, 3, 1.0
+deposit, 1, 4, 1.0
+deposit, 1, 5, 1.0");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    ledger.set_audit(AuditPolicy::default());
+    ledger.set_authorization_expiry(2);
+    let (journal, entries) = mpsc::channel();
+    ledger.set_journal(Box::new(journal));
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut IgnoreRejections).unwrap();
+    drop(ledger.take_journal());
+    let events: Vec<(Option<u64>, u32, JournalEvent)> = entries.iter().map(|e: JournalEntry| (e.line, e.tx, e.event)).collect();
+    assert_eq!(events[events.len() - 2..], [(Some(6), 5, JournalEvent::Credit), (Some(6), 2, JournalEvent::Expire)]);
+    assert_eq!(ledger.get_funds_available(1).unwrap(), Decimal::new(7, 0));
+    assert_eq!(ledger.expired(), &[2]);
+    ledger.check_trial_balance().unwrap();
+}
+
+// a statement shows the authorized funds next to held while any line's account has some, so the balances add up
+#[test]
+fn statement_shows_authorized() {
+    let input = "type, client, tx, amount
+deposit, 1, 1, 10.0
+authorize, 1, 2, 4.0
+capture, 1, 2, 1.5";
+    let statement = |format: StatementFormat| {
+        let rdr = stringreader::StringReader::new(input);
+        let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+        let mut out: Vec<u8> = Vec::new();
+        statement::write_statement(VERBOSE, 1, &mut InMemoryLedger::default(), &mut transactions, format, &PrecisionTable::default(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(statement(StatementFormat::Csv), "line,type,tx,amount,status,reason,available,held,authorized,total,locked
+2,deposit,1,10,accepted,,10.0000,0.0000,0.0000,10.0000,false
+3,authorize,2,4,accepted,,6.0000,0.0000,4.0000,10.0000,false
+4,capture,2,1.5,accepted,,8.5000,0.0000,0.0000,8.5000,false
+");
+    assert!(statement(StatementFormat::Text).lines().nth(1).unwrap().contains("held      authorized           total"));
 }
@@ -0,0 +1,120 @@
+use bankex::{precision::{PrecisionTable, Rounding}, server::Server};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::thread;
+
+static VERBOSE: bool = false;
+
+// sends rows on a fresh connection and returns everything the server wrote back
+fn send(addr: SocketAddr, rows: &str) -> Vec<String> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(rows.as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    BufReader::new(stream).lines().map(|l| l.unwrap()).collect()
+}
+
+#[test]
+fn concurrent_connections_share_one_ledger() {
+    let server = Server::bind("127.0.0.1:0", 4, PrecisionTable::default(), VERBOSE).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let partners: Vec<_> = (1..=4u32).map(|client| {
+        thread::spawn(move || {
+            let mut rows = String::from("type, client, tx, amount\n");
+            for i in 0..50u32 {
+                rows.push_str(&format!("deposit, {}, {}, 1.0\n", client, client * 1000 + i));
+            }
+            rows.push_str(&format!("withdrawal, {}, {}, 100.0\n", client, client * 1000 + 999));
+            send(addr, &rows)
+        })
+    }).collect();
+    for partner in partners {
+        let answer = partner.join().unwrap();
+        assert_eq!(answer.len(), 1);
+        assert!(answer[0].starts_with("52,withdrawal,"));
+        assert!(answer[0].contains(",insufficient_funds,"));
+    }
+
+    let report = send(addr, "report\n");
+    assert_eq!(report, vec![
+        "client,available,held,total,locked",
+        "1,50.0000,0.0000,50.0000,false",
+        "2,50.0000,0.0000,50.0000,false",
+        "3,50.0000,0.0000,50.0000,false",
+        "4,50.0000,0.0000,50.0000,false",
+        "",
+    ]);
+}
+
+// clients 1 and 2 are in different shards, transfers both ways hold both shards without deadlocking
+#[test]
+fn transfers_across_shards() {
+    let server = Server::bind("127.0.0.1:0", 2, PrecisionTable::default(), VERBOSE).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    assert!(send(addr, "type, client, tx, amount\ndeposit, 1, 1, 100.0\ndeposit, 2, 2, 100.0\n").is_empty());
+
+    let partners: Vec<_> = [(1u32, 2u32), (2, 1)].iter().map(|&(client, destination)| {
+        thread::spawn(move || {
+            let mut rows = String::from("type, client, tx, amount, destination\n");
+            for i in 0..50u32 {
+                rows.push_str(&format!("transfer, {}, {}, 1.0, {}\n", client, client * 1000 + i, destination));
+            }
+            send(addr, &rows)
+        })
+    }).collect();
+    for partner in partners {
+        assert!(partner.join().unwrap().is_empty());
+    }
+    // every rejected row of the connection has the destination column, empty when the row has none
+    let answer = send(addr, "type, client, tx, amount, destination\ntransfer, 1, 3, 1.0, 1\nwithdrawal, 1, 4, 1000.0,\n");
+    assert!(answer[0].starts_with("2,transfer,1,3,1,self_transfer,") && answer[0].ends_with(",1"), "{:?}", answer);
+    assert!(answer[1].starts_with("3,withdrawal,1,4,1000,insufficient_funds,") && answer[1].ends_with(","), "{:?}", answer);
+    assert_eq!(answer[0].split(',').count(), answer[1].split(',').count());
+    assert!(send(addr, "type, client, tx, amount\ndispute, 1, 1000,\nchargeback, 1, 1000,\n").is_empty());
+
+    let report = send(addr, "report\n");
+    assert_eq!(report, vec![
+        "client,available,held,total,locked",
+        "1,101.0000,0.0000,101.0000,false",
+        "2,99.0000,0.0000,99.0000,true",
+        "",
+    ]);
+}
+
+// rows are read and the report written with the places the server was started with
+#[test]
+fn asset_places() {
+    let precision = PrecisionTable::from_spec("JPY=0").unwrap().with_rounding(Rounding::Reject);
+    let server = Server::bind("127.0.0.1:0", 2, precision, VERBOSE).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    let answer = send(addr, "type, client, tx, amount, asset\ndeposit, 1, 1, 5, JPY\ndeposit, 1, 2, 1.5, JPY\nreport\n");
+    assert!(answer[0].starts_with("3,deposit,1,2,") && answer[0].ends_with(",JPY"), "{:?}", answer);
+    assert_eq!(answer[1..], [
+        "client,asset,available,held,total,locked",
+        "1,JPY,5,0,5,false",
+        "",
+    ]);
+}
+
+// a tx id already used by a client of another shard is a duplicate, and can not be disputed by the second client
+#[test]
+fn duplicate_tx_across_shards() {
+    let server = Server::bind("127.0.0.1:0", 2, PrecisionTable::default(), VERBOSE).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    assert!(send(addr, "type, client, tx, amount\ndeposit, 1, 1, 5.0\n").is_empty());
+    let answer = send(addr, "type, client, tx, amount\ndeposit, 2, 1, 7.0\ndispute, 2, 1,\n");
+    assert_eq!(answer.len(), 2, "{:?}", answer);
+    assert!(answer[0].starts_with("2,deposit,2,1,7") && answer[0].contains(",duplicate_tx,"), "{:?}", answer);
+    assert!(answer[1].starts_with("3,dispute,2,1,") && answer[1].contains(",client_mismatch,"), "{:?}", answer);
+
+    let report = send(addr, "report\n");
+    assert_eq!(report, vec![
+        "client,available,held,total,locked",
+        "1,5.0000,0.0000,5.0000,false",
+        "",
+    ]);
+}
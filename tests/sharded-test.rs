@@ -0,0 +1,98 @@
+use bankex::{Ledger, RunSummary, ledger::InMemoryLedger, policy::LockedAccountPolicy, read, rejected::Rejection, sharded::ShardedLedger};
+
+static VERBOSE: bool = false;
+static MIXED_CLIENTS_EXAMPLE: &str = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 3.0
+deposit, 3, 3, 1.0
+dispute, 1, 1,
+withdrawal, 1, 4, 1.0
+withdrawal, 2, 5, 1.0
+chargeback, 1, 1,
+deposit, 1, 6, 2.0
+deposit, 4, 7, 8.0
+dispute, 2, 2,
+resolve, 2, 2,
+withdrawal, 3, 8, 4.0
+deposit, 2, 1, 4.0
+dispute, 3, 2,
+withdrawal, 3, 7, 1.0
+withdrawal, 2, 9, 100.0
+deposit, 3, 9, 1.0
+dispute, 3, 9,";
+
+fn run(ledger: &mut dyn Ledger) -> (String, Vec<Rejection>) {
+    let rdr = stringreader::StringReader::new(MIXED_CLIENTS_EXAMPLE);
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut rejections: Vec<Rejection> = Vec::new();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    rejections.sort_by_key(|r| r.line);
+    let mut out: Vec<u8> = Vec::new();
+    ledger.run_report(&mut out).unwrap();
+    (String::from_utf8(out).unwrap(), rejections)
+}
+
+// per client ordering is kept so the sharded run reports exactly what a single thread does
+#[test]
+fn sharded_matches_single_thread() {
+    let expected = run(&mut InMemoryLedger::default());
+    // tx ids reused or disputed by a client of another shard than their owner's
+    let reasons: Vec<(Option<u64>, &str)> = expected.1.iter().filter(|r| r.line > Some(13)).map(|r| (r.line, r.reason)).collect();
+    assert_eq!(reasons, [(Some(14), "duplicate_tx"), (Some(15), "client_mismatch"), (Some(16), "duplicate_tx"), (Some(17), "insufficient_funds")]);
+    for workers in 1..=4 {
+        let mut ledger = ShardedLedger::new(workers);
+        assert_eq!(run(&mut ledger), expected);
+        assert_eq!(ledger.shards().len(), workers);
+    }
+}
+
+// rows rounded when read go to the exceptions from the workers, and from a transfer across shards, as on one ledger
+#[test]
+fn sharded_lists_rounding() {
+    let run = |ledger: &mut dyn Ledger| {
+        let rdr = stringreader::StringReader::new("type, client, tx, amount, destination
+deposit, 1, 1, 1.00005,
+deposit, 2, 2, 1.00015,
+withdrawal, 2, 3, 5.00005,
+transfer, 1, 4, 0.50005, 2
+deposit, 3, 5, 2.5,");
+        let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+        let mut rejections: Vec<Rejection> = Vec::new();
+        let summary = ledger.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+        rejections.sort_by_key(|r| r.line);
+        (summary, rejections)
+    };
+    let (summary, rejections) = run(&mut InMemoryLedger::default());
+    assert_eq!(summary, RunSummary { processed: 5, accepted: 4, rejected: 1 });
+    let reasons: Vec<(Option<u64>, &str)> = rejections.iter().map(|r| (r.line, r.reason)).collect();
+    assert_eq!(reasons, [(Some(2), "rounded"), (Some(3), "rounded"), (Some(4), "insufficient_funds"), (Some(5), "rounded")]);
+    for workers in 2..=3 {
+        assert_eq!(run(&mut ShardedLedger::new(workers)), (summary, rejections.clone()));
+    }
+}
+
+// a snapshot loaded into the shards keeps the locked account policy they were set up with
+#[test]
+fn sharded_snapshot_keeps_locked_policy() {
+    let rdr = stringreader::StringReader::new("type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 3.0
+deposit, 2, 3, 2.0
+dispute, 1, 1,
+chargeback, 1, 1,");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut ledger = InMemoryLedger::default();
+    ledger.read_transactions(VERBOSE, &mut transactions, &mut Vec::new()).unwrap();
+    let mut snapshot: Vec<u8> = Vec::new();
+    ledger.save_snapshot(&mut snapshot).unwrap();
+
+    let mut sharded = ShardedLedger::new(2);
+    sharded.set_locked_policy(LockedAccountPolicy::allowing("dispute").unwrap());
+    sharded.load_snapshot(&mut snapshot.as_slice()).unwrap();
+    let rdr = stringreader::StringReader::new("type, client, tx, amount\ndispute, 1, 2,");
+    let mut transactions = read::transaction_reader_from(VERBOSE, Box::new(rdr)).unwrap();
+    let mut rejections: Vec<Rejection> = Vec::new();
+    sharded.read_transactions(VERBOSE, &mut transactions, &mut rejections).unwrap();
+    assert!(rejections.is_empty(), "{:?}", rejections);
+    assert_eq!(sharded.get_funds_held(1).unwrap(), rust_decimal::Decimal::new(3, 0));
+}